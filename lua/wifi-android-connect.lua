@@ -4,7 +4,8 @@ local function setup(opts)
         local default_ops = {
                 pair_code = nil,
                 pair_name = "WIFI Android Connect nvim",
-                timeout_in_seconds = 2*60
+                timeout_in_seconds = 2*60,
+                backend = "mdns-sd"
         }
         opts = opts or default_ops
         ffi_lib.setup(opts)
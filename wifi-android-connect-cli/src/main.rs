@@ -1,46 +1,217 @@
-use wifi_android_connect_lib::WifiAndroidConnect;
+use std::{path::PathBuf, time::Duration};
 
-use clap::Parser;
+#[cfg(unix)]
+use wifi_android_connect_lib::{default_socket_path, DaemonClient, PairingRequest};
+use wifi_android_connect_lib::{
+    DiscoveryBackend, InterfaceMatcher, PairCode, SessionReplay, WifiAndroidConnect,
+};
+
+use clap::{Parser, Subcommand};
 
 /// WIFI Android Connect: A CLI tool to connect to wireless debugging using a QR code in the terminal.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct WifiAndroidConnectArgs {
-    /// Name of adb service
+    /// Prefix of the adb service name, a random token is appended to it on every run
     #[arg(short = 'n', long = "name")]
     pair_name: Option<String>,
 
     /// 6 digits pair code
     #[arg(short = 'c', long)]
-    code: Option<u32>,
+    code: Option<PairCode>,
+
+    /// Give up after waiting this many seconds for the device
+    #[arg(short, long)]
+    timeout: Option<u64>,
+
+    /// mDNS backend used to discover the device: mdns-sd (default), zeroconf, avahi or adb-server
+    #[arg(short, long)]
+    backend: Option<DiscoveryBackend>,
+
+    /// Don't ask the adb server for mDNS services when the backend fails to start
+    #[arg(long)]
+    no_adb_server_fallback: bool,
+
+    /// Only discover devices on this network interface, by name (wlan0) or subnet (192.168.0.0/24)
+    #[arg(short, long = "interface")]
+    interfaces: Vec<InterfaceMatcher>,
+
+    /// Never discover devices on this network interface, by name (docker0) or subnet (172.17.0.0/16)
+    #[arg(long = "exclude-interface")]
+    exclude_interfaces: Vec<InterfaceMatcher>,
+
+    /// Also print the QR code payload, e.g. for wifi-android-connect-sim
+    #[arg(long)]
+    payload: bool,
+
+    /// Record the discovered services and adb calls to this file
+    #[arg(long, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Replay a session recorded with --record instead of discovering devices
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Print each pairing and connection step
+    #[arg(short, long)]
+    verbose: bool,
 
     /// show the logs
     #[arg(short, long)]
     debug: bool,
+
+    /// Socket of the daemon, pairing goes through the daemon when it's running and no discovery option is given
+    #[cfg(unix)]
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Manage the devices already paired, instead of connecting a new one
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the devices known by the adb server and their state
+    Devices,
+    /// Disconnect the device at this address, e.g. 192.168.0.197:34003
+    Disconnect { address: String },
+    /// Disconnect the device with this serial and stop adb auto-connecting to it (restart the adb server after)
+    Forget { serial: String },
+    /// Keep the paired devices connected, reconnecting them when they drop or change port
+    Watch,
+    /// Run in the background with discovery always on, for the CLI and neovim to pair through
+    #[cfg(unix)]
+    Daemon,
+    /// Connect the device with this serial where it advertises, through the daemon
+    #[cfg(unix)]
+    Reconnect { serial: String },
+}
+
+impl Command {
+    /// Whether the command discovers devices, with the connection options.
+    fn discovers(&self) -> bool {
+        match self {
+            Command::Watch => true,
+            #[cfg(unix)]
+            Command::Daemon => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl WifiAndroidConnectArgs {
+    /// Whether discovery options are given, the daemon discovers with its own.
+    fn configures_discovery(&self) -> bool {
+        self.backend.is_some()
+            || self.no_adb_server_fallback
+            || !self.interfaces.is_empty()
+            || !self.exclude_interfaces.is_empty()
+    }
 }
 
 fn main() {
     let args = WifiAndroidConnectArgs::parse();
     if args.debug {
         env_logger::builder()
-            .filter_module("wifi_android_connect_lib:",log::LevelFilter::Trace )
+            .filter_module("wifi_android_connect_lib:", log::LevelFilter::Trace)
             .init();
     }
 
-    let mut con = WifiAndroidConnect::default();
+    #[cfg(unix)]
+    let daemon = DaemonClient::new(args.socket.clone().unwrap_or_else(default_socket_path));
+
+    #[cfg(unix)]
+    let configures_discovery = args.configures_discovery();
+    let command = match args.command {
+        Some(command) if !command.discovers() => {
+            #[cfg(unix)]
+            manage(command, &daemon);
+            #[cfg(not(unix))]
+            manage(command);
+            return;
+        }
+        command => command,
+    };
+
+    #[cfg(unix)]
+    if command.is_none()
+        && args.record.is_none()
+        && args.replay.is_none()
+        && !configures_discovery
+        && daemon.is_running()
+    {
+        let request = PairingRequest {
+            name_prefix: args.pair_name,
+            pair_code: args.code.map(|code| code.to_string()),
+            timeout_secs: args.timeout,
+        };
+        pair_through_daemon(&daemon, &request, args.payload, args.verbose);
+        return;
+    }
+
+    if let Some(path) = args.replay {
+        replay(&path, args.timeout, args.verbose);
+        return;
+    }
+
+    let mut builder = WifiAndroidConnect::builder()
+        .backend(args.backend.unwrap_or_default())
+        .adb_server_fallback(!args.no_adb_server_fallback);
+
+    if args.verbose {
+        builder = builder.on_state_change(|transition| println!("{}", transition.to));
+    }
+
+    if let Some(path) = args.record {
+        builder = builder.record(path);
+    }
 
     if let Some(name) = args.pair_name {
-        con.pair_name = name;
+        builder = builder.pair_name_prefix(&name);
     }
 
     if let Some(code) = args.code {
-        con.pair_code = code;
+        builder = builder.pair_code(code);
+    }
+
+    for interface in args.interfaces {
+        builder = builder.include_interface(interface);
+    }
+
+    for interface in args.exclude_interfaces {
+        builder = builder.exclude_interface(interface);
+    }
+
+    if let Some(timeout) = args.timeout {
+        builder = builder.timeout(Duration::from_secs(timeout));
+    }
+
+    let con = match builder.build() {
+        Ok(con) => con,
+        Err(msg) => {
+            println!("ERROR: {msg}");
+            return;
+        }
+    };
+
+    if let Some(command) = command {
+        let result = match command {
+            #[cfg(unix)]
+            Command::Daemon => con.daemon(args.socket.unwrap_or_else(default_socket_path)),
+            _ => con.watch(|event| println!("{event}")),
+        };
+        if let Err(e) = result {
+            println!("ERROR: {e}");
+        }
+        return;
     }
 
     log::trace!(
         "service name: {}, pair code {}",
-        con.pair_name,
-        con.pair_code
+        con.pair_name(),
+        con.pair_code()
     );
 
     match con.qrcode_img() {
@@ -51,6 +222,16 @@ fn main() {
         }
     }
 
+    if args.payload {
+        match con.qrcode_payload() {
+            Ok(payload) => println!("{payload}"),
+            Err(msg) => {
+                println!("ERROR: {msg}");
+                return;
+            }
+        }
+    }
+
     match con.connect() {
         Ok(_) => {
             println!("Connected")
@@ -58,3 +239,120 @@ fn main() {
         Err(e) => println!("ERROR: {e}"),
     }
 }
+
+fn replay(path: &PathBuf, timeout: Option<u64>, verbose: bool) {
+    let replay = match SessionReplay::open(path) {
+        Ok(replay) => replay,
+        Err(msg) => {
+            println!("ERROR: {msg}");
+            return;
+        }
+    };
+
+    let mut builder = WifiAndroidConnect::builder()
+        .pair_name(replay.pair_name())
+        .pair_code(*replay.pair_code());
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(Duration::from_secs(timeout));
+    }
+    if verbose {
+        builder = builder.on_state_change(|transition| println!("{}", transition.to));
+    }
+    let con = match builder.build() {
+        Ok(con) => con,
+        Err(msg) => {
+            println!("ERROR: {msg}");
+            return;
+        }
+    };
+
+    match con.connect_with(&replay, &replay) {
+        Ok(_) => println!("Connected"),
+        Err(e) => println!("ERROR: {e}"),
+    }
+    if !replay.is_finished() {
+        println!("WARNING: the replay stopped before the end of the recording");
+    }
+}
+
+#[cfg(unix)]
+fn pair_through_daemon(
+    daemon: &DaemonClient,
+    request: &PairingRequest,
+    payload: bool,
+    verbose: bool,
+) {
+    let session = match daemon.start_pairing(request) {
+        Ok(session) => session,
+        Err(msg) => {
+            println!("ERROR: {msg}");
+            return;
+        }
+    };
+    println!("{}", session.qrcode);
+    if payload {
+        println!("{}", session.payload);
+    }
+    let result = daemon.wait(session.session, |state| {
+        if verbose {
+            println!("{state}");
+        }
+    });
+    match result {
+        Ok(()) => println!("Connected"),
+        Err(e) => println!("ERROR: {e}"),
+    }
+}
+
+/// Goes through the daemon when it's running.
+#[cfg(unix)]
+fn manage(command: Command, daemon: &DaemonClient) {
+    if !daemon.is_running() {
+        return manage_locally(command);
+    }
+    let result = match command {
+        Command::Devices => daemon.list_devices().map(|devices| {
+            for device in devices {
+                println!("{device}");
+            }
+        }),
+        Command::Reconnect { serial } => daemon
+            .reconnect(&serial)
+            .map(|address| println!("Connected {serial} on {address}")),
+        command => return manage_locally(command),
+    };
+    if let Err(msg) = result {
+        println!("ERROR: {msg}");
+    }
+}
+
+#[cfg(not(unix))]
+fn manage(command: Command) {
+    manage_locally(command)
+}
+
+fn manage_locally(command: Command) {
+    let con = WifiAndroidConnect::default();
+    let result = match command {
+        Command::Devices => con.list_devices().map(|devices| {
+            for device in devices {
+                println!("{device}");
+            }
+        }),
+        Command::Disconnect { address } => con
+            .disconnect(&address)
+            .map(|()| println!("Disconnected {address}")),
+        Command::Forget { serial } => con.forget(&serial).map(|()| {
+            println!("adb won't connect {serial} on its own anymore, restart it with `adb kill-server`");
+            println!("The device still trusts this computer, revoke it in its wireless debugging settings");
+        }),
+        #[cfg(unix)]
+        Command::Reconnect { .. } => Err(
+            "reconnect needs a running daemon, start it with `wifi-android-connect daemon`".into(),
+        ),
+        command => unreachable!("{command:?} needs the connection options"),
+    };
+    if let Err(msg) = result {
+        println!("ERROR: {msg}");
+    }
+}
@@ -0,0 +1,576 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+    StreamExt,
+};
+use zbus::{
+    message::Type as MessageType, zvariant::OwnedObjectPath, Connection, MatchRule, Message,
+    MessageStream, Proxy,
+};
+
+use crate::{
+    adb_device_authentication::AdbService,
+    adb_mdns_discovery_service::{
+        AdbMDnsDiscoveryService, AdbServiceEvent, AdbServiceType, ServiceRegistry,
+    },
+};
+
+const AVAHI_SERVICE: &str = "org.freedesktop.Avahi";
+const AVAHI_SERVER_INTERFACE: &str = "org.freedesktop.Avahi.Server";
+const AVAHI_BROWSER_INTERFACE: &str = "org.freedesktop.Avahi.ServiceBrowser";
+
+const AVAHI_IF_UNSPEC: i32 = -1;
+const AVAHI_PROTO_UNSPEC: i32 = -1;
+const AVAHI_PROTO_INET: i32 = 0;
+
+/// Body of the `ItemNew` and `ItemRemove` signals: interface, protocol, name,
+/// type, domain and flags.
+type BrowserItem = (i32, i32, String, String, String, u32);
+
+/// Reply of `ResolveService`: interface, protocol, name, type, domain, host,
+/// address protocol, address, port, txt and flags.
+type ResolvedService = (
+    i32,
+    i32,
+    String,
+    String,
+    String,
+    String,
+    i32,
+    String,
+    u16,
+    Vec<Vec<u8>>,
+    u32,
+);
+
+struct BrowseSession {
+    connection: Connection,
+    browsers: Vec<OwnedObjectPath>,
+    stop: oneshot::Sender<()>,
+    worker: JoinHandle<()>,
+}
+
+/// Discovery backend talking to avahi-daemon over D-Bus, so it doesn't compete
+/// with the daemon for the mDNS port.
+pub struct AdbAvahi {
+    connection: Option<Connection>,
+    session: Mutex<Option<BrowseSession>>,
+    registry: Arc<Mutex<ServiceRegistry>>,
+}
+
+async fn server_proxy(connection: &Connection) -> zbus::Result<Proxy<'static>> {
+    Proxy::new(connection, AVAHI_SERVICE, "/", AVAHI_SERVER_INTERFACE).await
+}
+
+async fn resolve(connection: &Connection, item: BrowserItem) -> Result<AdbService, String> {
+    let (interface, protocol, name, service_type, domain, _flags) = item;
+    let server = server_proxy(connection).await.map_err(|e| e.to_string())?;
+
+    let resolved: ResolvedService = server
+        .call(
+            "ResolveService",
+            &(
+                interface,
+                protocol,
+                name,
+                service_type,
+                domain,
+                AVAHI_PROTO_INET,
+                0u32,
+            ),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    let (_, _, name, service_type, domain, host, _, address, port, txt, _) = resolved;
+    let service_type = AdbServiceType::from_service_type(&service_type)
+        .ok_or_else(|| format!("Unexpected service type {service_type}"))?;
+
+    Ok(AdbService {
+        name,
+        addresses: vec![address.clone()],
+        ip: address,
+        port,
+        domain,
+        service_type,
+        hostname: host,
+        txt: txt.iter().map(|entry| parse_txt_entry(entry)).collect(),
+    })
+}
+
+/// Splits a `key=value` TXT record entry, a key without `=` has an empty value.
+fn parse_txt_entry(entry: &[u8]) -> (String, String) {
+    let entry = String::from_utf8_lossy(entry);
+    match entry.split_once('=') {
+        Some((key, value)) => (key.into(), value.into()),
+        None => (entry.into_owned(), String::new()),
+    }
+}
+
+async fn on_browser_signal(
+    connection: &Connection,
+    msg: Message,
+    browsers: &HashMap<OwnedObjectPath, AdbServiceType>,
+    registry: &Mutex<ServiceRegistry>,
+) {
+    let header = msg.header();
+    let (Some(path), Some(member)) = (header.path(), header.member()) else {
+        return;
+    };
+    let Some(&service_type) = browsers.get(&OwnedObjectPath::from(path.to_owned())) else {
+        return;
+    };
+
+    match member.as_str() {
+        "ItemNew" => {
+            let item: BrowserItem = match msg.body().deserialize() {
+                Ok(item) => item,
+                Err(e) => {
+                    log::error!("Invalid ItemNew signal: {e}");
+                    return;
+                }
+            };
+            match resolve(connection, item).await {
+                Ok(service) => {
+                    log::info!("Avahi resolved service: {service:?}");
+                    registry.lock().unwrap().resolved(service_type, service);
+                }
+                Err(e) => log::warn!("Failed to resolve service: {e}"),
+            }
+        }
+        "ItemRemove" => {
+            let item: BrowserItem = match msg.body().deserialize() {
+                Ok(item) => item,
+                Err(e) => {
+                    log::error!("Invalid ItemRemove signal: {e}");
+                    return;
+                }
+            };
+            let (_, _, name, _, _, _) = item;
+            log::info!("Avahi removed service: {name}");
+            registry.lock().unwrap().removed(service_type, &name);
+        }
+        "Failure" => {
+            let error: Result<String, _> = msg.body().deserialize();
+            log::error!("Avahi service browser failure: {error:?}");
+        }
+        member => log::trace!("ignoring avahi browser signal {member}"),
+    }
+}
+
+fn browse(
+    connection: Connection,
+    mut signals: MessageStream,
+    browsers: HashMap<OwnedObjectPath, AdbServiceType>,
+    registry: Arc<Mutex<ServiceRegistry>>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    zbus::block_on(async move {
+        loop {
+            match select(signals.next(), &mut stop).await {
+                Either::Left((Some(Ok(msg)), _)) => {
+                    on_browser_signal(&connection, msg, &browsers, &registry).await
+                }
+                Either::Left((Some(Err(e)), _)) => log::error!("D-Bus error: {e}"),
+                Either::Left((None, _)) | Either::Right(_) => break,
+            }
+        }
+    })
+}
+
+impl AdbMDnsDiscoveryService for AdbAvahi {
+    fn start(&self) -> Result<(), String> {
+        let mut session = self.session.lock().unwrap();
+        if session.is_some() {
+            return Err("avahi discovery already started".into());
+        }
+
+        let new_session = zbus::block_on(async {
+            let connection = match &self.connection {
+                Some(connection) => connection.clone(),
+                None => Connection::system().await?,
+            };
+
+            // Subscribe before creating the browsers, otherwise the first
+            // signals may be emitted before we know the browsers' paths.
+            let rule = MatchRule::builder()
+                .msg_type(MessageType::Signal)
+                .interface(AVAHI_BROWSER_INTERFACE)?
+                .build();
+            let signals = MessageStream::for_match_rule(rule, &connection, None).await?;
+
+            let server = server_proxy(&connection).await?;
+            let mut browsers = HashMap::new();
+            for service_type in AdbServiceType::ALL {
+                let path: OwnedObjectPath = server
+                    .call(
+                        "ServiceBrowserNew",
+                        &(
+                            AVAHI_IF_UNSPEC,
+                            AVAHI_PROTO_UNSPEC,
+                            service_type.as_str(),
+                            "",
+                            0u32,
+                        ),
+                    )
+                    .await?;
+                browsers.insert(path, service_type);
+            }
+
+            let (stop, stop_rx) = oneshot::channel();
+            let paths = browsers.keys().cloned().collect();
+            let worker_connection = connection.clone();
+            let registry = self.registry.clone();
+            let worker = std::thread::spawn(move || {
+                browse(worker_connection, signals, browsers, registry, stop_rx)
+            });
+
+            Ok::<_, zbus::Error>(BrowseSession {
+                connection,
+                browsers: paths,
+                stop,
+                worker,
+            })
+        })
+        .map_err(|e| e.to_string())?;
+
+        *session = Some(new_session);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), String> {
+        let Some(session) = self.session.lock().unwrap().take() else {
+            return Ok(());
+        };
+
+        let _ = session.stop.send(());
+        session
+            .worker
+            .join()
+            .map_err(|_| "avahi worker panicked".to_string())?;
+
+        zbus::block_on(async {
+            for path in &session.browsers {
+                session
+                    .connection
+                    .call_method(
+                        Some(AVAHI_SERVICE),
+                        path,
+                        Some(AVAHI_BROWSER_INTERFACE),
+                        "Free",
+                        &(),
+                    )
+                    .await?;
+            }
+            Ok::<_, zbus::Error>(())
+        })
+        .map_err(|e| e.to_string())
+    }
+
+    fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Pairing)
+    }
+
+    fn adb_tls_connect(&self) -> HashSet<AdbService> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Connect)
+    }
+
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        self.registry.lock().unwrap().take_events()
+    }
+}
+
+impl AdbAvahi {
+    /// Uses avahi-daemon on the system bus, connecting on `start`.
+    pub fn new() -> AdbAvahi {
+        AdbAvahi {
+            connection: None,
+            session: Default::default(),
+            registry: Default::default(),
+        }
+    }
+
+    /// Uses the Avahi server reachable through `connection`.
+    pub fn with_connection(connection: Connection) -> AdbAvahi {
+        AdbAvahi {
+            connection: Some(connection),
+            ..AdbAvahi::new()
+        }
+    }
+}
+
+impl Default for AdbAvahi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use zbus::{connection::Builder, object_server::SignalEmitter};
+
+    use super::*;
+
+    /// `(name, type, address, port)` of a service advertised by the mock.
+    type MockService = (&'static str, &'static str, &'static str, u16);
+
+    struct MockAvahiServer {
+        services: Vec<MockService>,
+        browser_count: AtomicUsize,
+        freed: Arc<AtomicUsize>,
+    }
+
+    struct MockServiceBrowser {
+        freed: Arc<AtomicUsize>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Avahi.ServiceBrowser")]
+    impl MockServiceBrowser {
+        fn free(&self) {
+            self.freed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Avahi.Server")]
+    impl MockAvahiServer {
+        #[allow(clippy::too_many_arguments)]
+        async fn service_browser_new(
+            &self,
+            #[zbus(object_server)] server: &zbus::ObjectServer,
+            #[zbus(connection)] connection: &Connection,
+            _interface: i32,
+            _protocol: i32,
+            service_type: String,
+            _domain: String,
+            _flags: u32,
+        ) -> zbus::fdo::Result<OwnedObjectPath> {
+            let id = self.browser_count.fetch_add(1, Ordering::SeqCst);
+            let path = format!("/Client1/ServiceBrowser{id}");
+            let freed = self.freed.clone();
+            server
+                .at(path.as_str(), MockServiceBrowser { freed })
+                .await?;
+
+            let emitter = SignalEmitter::new(connection, path.clone())?;
+            for (name, kind, _, _) in &self.services {
+                if *kind != service_type {
+                    continue;
+                }
+                let item: BrowserItem = (
+                    2,
+                    0,
+                    name.to_string(),
+                    service_type.clone(),
+                    "local".into(),
+                    0,
+                );
+                emitter
+                    .emit(AVAHI_BROWSER_INTERFACE, "ItemNew", &item)
+                    .await?;
+            }
+            emitter
+                .emit(AVAHI_BROWSER_INTERFACE, "AllForNow", &())
+                .await?;
+
+            Ok(OwnedObjectPath::try_from(path).unwrap())
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn resolve_service(
+            &self,
+            interface: i32,
+            protocol: i32,
+            name: String,
+            service_type: String,
+            domain: String,
+            aprotocol: i32,
+            _flags: u32,
+        ) -> zbus::fdo::Result<ResolvedService> {
+            let (_, _, address, port) = self
+                .services
+                .iter()
+                .find(|(n, t, _, _)| *n == name && *t == service_type)
+                .ok_or_else(|| zbus::fdo::Error::Failed(format!("unknown service {name}")))?;
+
+            Ok((
+                interface,
+                protocol,
+                name,
+                service_type,
+                domain,
+                "android.local".into(),
+                aprotocol,
+                address.to_string(),
+                *port,
+                vec![b"v=1".to_vec()],
+                0,
+            ))
+        }
+    }
+
+    /// A `dbus-daemon --session` of its own, killed on drop with the mock
+    /// avahi-daemon it serves.
+    struct PrivateBus {
+        daemon: Child,
+        _mock: Connection,
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Serves a mock avahi-daemon on a private session bus, returns a client
+    /// connection to that bus and how many service browsers were freed.
+    fn mock_avahi(services: Vec<MockService>) -> (PrivateBus, Connection, Arc<AtomicUsize>) {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("the avahi tests need dbus-daemon");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+
+        let freed = Arc::new(AtomicUsize::new(0));
+        let mock = MockAvahiServer {
+            services,
+            browser_count: AtomicUsize::new(0),
+            freed: freed.clone(),
+        };
+        let (mock, client) = zbus::block_on(async {
+            let mock = Builder::address(address.trim())
+                .unwrap()
+                .name(AVAHI_SERVICE)
+                .unwrap()
+                .serve_at("/", mock)
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            let client = Builder::address(address.trim())
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            (mock, client)
+        });
+
+        (
+            PrivateBus {
+                daemon,
+                _mock: mock,
+            },
+            client,
+            freed,
+        )
+    }
+
+    fn wait_for(mut condition: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    /// A service as resolved by the mock.
+    fn resolved(service_type: AdbServiceType, name: &str, port: u16) -> AdbService {
+        AdbService {
+            hostname: "android.local".into(),
+            txt: [("v".to_string(), "1".to_string())].into(),
+            ..AdbService::new(service_type, name, "192.168.0.197", port)
+        }
+    }
+
+    #[test]
+    fn test_parse_txt_entry() {
+        assert_eq!(parse_txt_entry(b"v=1"), ("v".into(), "1".into()));
+        assert_eq!(parse_txt_entry(b"k=a=b"), ("k".into(), "a=b".into()));
+        assert_eq!(parse_txt_entry(b"flag"), ("flag".into(), String::new()));
+    }
+
+    #[test]
+    fn test_discover_services() {
+        let (_bus, connection, freed) = mock_avahi(vec![
+            (
+                "studio-k3J9aZ",
+                "_adb-tls-pairing._tcp",
+                "192.168.0.197",
+                34317,
+            ),
+            (
+                "adb-wg858lj7t959helz-si5LWZ",
+                "_adb-tls-connect._tcp",
+                "192.168.0.197",
+                34003,
+            ),
+            ("printer", "_ipp._tcp", "192.168.0.10", 631),
+        ]);
+        let avahi = AdbAvahi::with_connection(connection);
+
+        avahi.start().unwrap();
+        assert!(wait_for(
+            || !avahi.adb_tls_pairing().is_empty() && !avahi.adb_tls_connect().is_empty()
+        ));
+        avahi.stop().unwrap();
+        assert_eq!(freed.load(Ordering::SeqCst), 2);
+
+        let pairing = avahi.adb_tls_pairing();
+        let connect = avahi.adb_tls_connect();
+        assert_eq!(
+            pairing,
+            HashSet::from([resolved(AdbServiceType::Pairing, "studio-k3J9aZ", 34317)])
+        );
+        assert_eq!(
+            connect,
+            HashSet::from([resolved(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003
+            )])
+        );
+    }
+
+    #[test]
+    fn test_start_twice() {
+        let (_bus, connection, _freed) = mock_avahi(vec![(
+            "adb-wg858lj7t959helz-si5LWZ",
+            "_adb-tls-connect._tcp",
+            "192.168.0.197",
+            34003,
+        )]);
+        let avahi = AdbAvahi::with_connection(connection);
+
+        avahi.start().unwrap();
+        assert!(wait_for(|| !avahi.adb_tls_connect().is_empty()));
+        assert!(avahi.adb_tls_pairing().is_empty());
+        assert!(avahi.start().is_err());
+        avahi.stop().unwrap();
+    }
+}
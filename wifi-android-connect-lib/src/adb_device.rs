@@ -0,0 +1,127 @@
+use std::{fmt, str::FromStr};
+
+use crate::adb_instance_name::AdbInstanceName;
+
+/// State of a device's transport, as listed by `adb devices`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportState {
+    /// Connected and authorized, ready for adb commands.
+    Device,
+    Offline,
+    /// Waiting for the user to allow debugging on the device.
+    Unauthorized,
+    Authorizing,
+    Connecting,
+    /// Any other state, e.g. `recovery` or `no permissions`.
+    Other(String),
+}
+
+impl FromStr for TransportState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "device" => TransportState::Device,
+            "offline" => TransportState::Offline,
+            "unauthorized" => TransportState::Unauthorized,
+            "authorizing" => TransportState::Authorizing,
+            "connecting" => TransportState::Connecting,
+            other => TransportState::Other(other.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for TransportState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportState::Device => write!(f, "device"),
+            TransportState::Offline => write!(f, "offline"),
+            TransportState::Unauthorized => write!(f, "unauthorized"),
+            TransportState::Authorizing => write!(f, "authorizing"),
+            TransportState::Connecting => write!(f, "connecting"),
+            TransportState::Other(state) => write!(f, "{state}"),
+        }
+    }
+}
+
+/// A device known by the adb server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdbDevice {
+    /// `ip:port` for devices connected over TCP, the instance name with its
+    /// service type for the ones adb connected through mDNS, e.g.
+    /// `adb-wg858lj7t959helz-si5LWZ._adb-tls-connect._tcp`.
+    pub serial: String,
+    pub state: TransportState,
+}
+
+impl AdbDevice {
+    /// The device's instance name, when adb connected it through mDNS.
+    pub fn instance_name(&self) -> Option<AdbInstanceName> {
+        self.serial.parse().ok()
+    }
+
+    /// Whether the device is connected over the network, not USB.
+    pub fn is_wireless(&self) -> bool {
+        self.serial.contains("._adb-tls-connect.")
+            || self.serial.parse::<std::net::SocketAddr>().is_ok()
+    }
+}
+
+impl fmt::Display for AdbDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\t{}", self.serial, self.state)
+    }
+}
+
+/// Parses the `host:devices` reply, one `<serial>\t<state>` line per device.
+pub(crate) fn parse_devices(reply: &str) -> Result<Vec<AdbDevice>, String> {
+    reply
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (serial, state) = line
+                .split_once('\t')
+                .ok_or_else(|| format!("Unexpected device line: {line}"))?;
+            Ok(AdbDevice {
+                serial: serial.to_string(),
+                state: state.parse()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_devices() {
+        let reply = "192.168.0.197:34003\tdevice\n\
+                     adb-wg858lj7t959helz-si5LWZ._adb-tls-connect._tcp\tunauthorized\n\
+                     R58M123ABC\tno permissions\n";
+
+        let devices = parse_devices(reply).unwrap();
+
+        assert_eq!(devices[0].state, TransportState::Device);
+        assert!(devices[0].is_wireless());
+        assert_eq!(devices[0].instance_name(), None);
+        assert_eq!(devices[1].state, TransportState::Unauthorized);
+        assert!(devices[1].is_wireless());
+        assert_eq!(
+            devices[1].instance_name().map(|name| name.serial),
+            Some("wg858lj7t959helz".to_string())
+        );
+        assert_eq!(
+            devices[2].state,
+            TransportState::Other("no permissions".into())
+        );
+        assert!(!devices[2].is_wireless());
+        assert_eq!(devices[2].to_string(), "R58M123ABC\tno permissions");
+    }
+
+    #[test]
+    fn test_parse_no_devices() {
+        assert_eq!(parse_devices(""), Ok(vec![]));
+        assert!(parse_devices("garbage").is_err());
+    }
+}
@@ -1,90 +1,415 @@
-use std::collections::HashMap;
-
-use crate::client::AdbClient;
-
-#[derive(Debug)]
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+use crate::{
+    adb_instance_name::{strip_service_type, AdbInstanceName},
+    adb_mdns_discovery_service::{AdbServiceEvent, AdbServiceType},
+    client::{AdbClient, AsyncAdbClient},
+    pair_code::PairCode,
+};
+
+/// Where the authentication of the device is at.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AdbConnectionState {
-    Unpaired(String, u32),
+    /// Waiting for the pairing service named after the QR code.
+    Unpaired,
+    /// Pairing with the pairing service at `address`.
+    Pairing {
+        address: String,
+    },
+    /// Paired, waiting for the device's connect service.
     Paired,
-    Connected,
+    /// Connecting to the connect service at `address`.
+    Connecting {
+        address: String,
+    },
+    Connected {
+        address: String,
+    },
+    /// The last pair or connect attempt failed, the service isn't tried
+    /// again until it's advertised again or another one shows up.
+    Failed {
+        reason: String,
+    },
+    /// The connect service of the connected device isn't advertised anymore.
+    Disconnected,
+}
+
+impl fmt::Display for AdbConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdbConnectionState::Unpaired => {
+                f.write_str("Waiting for the device to scan the QR code")
+            }
+            AdbConnectionState::Pairing { address } => write!(f, "Pairing with {address}"),
+            AdbConnectionState::Paired => f.write_str("Paired"),
+            AdbConnectionState::Connecting { address } => write!(f, "Connecting to {address}"),
+            AdbConnectionState::Connected { address } => write!(f, "Connected to {address}"),
+            AdbConnectionState::Failed { reason } => write!(f, "Failed: {reason}"),
+            AdbConnectionState::Disconnected => f.write_str("Disconnected"),
+        }
+    }
+}
+
+/// A change of [`AdbConnectionState`], reported to the observers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdbStateTransition {
+    pub from: AdbConnectionState,
+    pub to: AdbConnectionState,
+}
+
+impl AdbStateTransition {
+    /// Whether the state machine allows going `from` a state `to` another.
+    pub fn is_valid(&self) -> bool {
+        use AdbConnectionState::*;
+        matches!(
+            (&self.from, &self.to),
+            (Unpaired | Failed { .. } | Disconnected, Pairing { .. })
+                | (Pairing { .. }, Paired | Failed { .. })
+                | (
+                    Unpaired | Paired | Failed { .. } | Connected { .. } | Disconnected,
+                    Connecting { .. }
+                )
+                | (Connecting { .. }, Connected { .. } | Failed { .. })
+                | (Connected { .. }, Disconnected)
+        )
+    }
+}
+
+/// Called with every [`AdbStateTransition`].
+#[derive(Clone)]
+pub(crate) struct StateObserver(Arc<dyn Fn(&AdbStateTransition) + Send + Sync>);
+
+impl StateObserver {
+    pub fn new(observer: impl Fn(&AdbStateTransition) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(observer))
+    }
+}
+
+impl fmt::Debug for StateObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StateObserver")
+    }
 }
 
 #[derive(Debug)]
 pub struct AdbDeviceAuthentication {
-    pub state: AdbConnectionState,
+    pair_name: String,
+    /// What the pairing service names of other sessions like ours start with.
+    name_prefix: String,
+    /// Pairing services already warned about.
+    conflicts: HashSet<String>,
+    pair_code: PairCode,
+    state: AdbConnectionState,
+    /// Whether pairing succeeded, the state only tells the last step.
+    paired: bool,
+    /// Addresses the last pair or connect attempt with failed.
+    failed: HashSet<String>,
+    observers: Vec<StateObserver>,
     pub known_address: HashMap<String, String>,
+    /// Ip of the devices seen through their connect service, by serial.
+    pub known_devices: HashMap<String, String>,
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub struct AdbService {
     pub name: String,
     pub ip: String,
     pub port: u16,
     pub domain: String,
+    pub service_type: AdbServiceType,
+    /// Host the service runs on, e.g. `Android.local.`, empty when the backend
+    /// doesn't report it.
+    pub hostname: String,
+    /// Every address the service resolved to, `ip` being the one used.
+    pub addresses: Vec<String>,
+    /// TXT record properties, values that aren't UTF-8 are replaced lossily.
+    pub txt: BTreeMap<String, String>,
 }
 
 impl AdbService {
+    /// A `local` service only known by its name and address.
+    pub fn new(
+        service_type: AdbServiceType,
+        name: impl Into<String>,
+        ip: impl Into<String>,
+        port: u16,
+    ) -> AdbService {
+        let ip = ip.into();
+        AdbService {
+            name: name.into(),
+            addresses: vec![ip.clone()],
+            ip,
+            port,
+            domain: "local".into(),
+            service_type,
+            hostname: String::new(),
+            txt: BTreeMap::new(),
+        }
+    }
+
+    pub fn txt(&self, key: &str) -> Option<&str> {
+        self.txt.get(key).map(String::as_str)
+    }
+
     pub fn address(&self) -> String {
         format!("{ip}:{port}", ip = self.ip, port = self.port)
     }
     pub fn ip(&self) -> &String {
         &self.ip
     }
+
+    /// The service instance name, without the `._adb-tls-*._tcp.local.` suffix
+    /// some backends append.
+    pub fn instance_name(&self) -> &str {
+        strip_service_type(&self.name)
+    }
+
+    /// The device serial and suffix of an adbd connect service, `None` for
+    /// other names like the pairing services'.
+    pub fn adb_instance_name(&self) -> Option<AdbInstanceName> {
+        self.instance_name().parse().ok()
+    }
 }
 
 impl AdbDeviceAuthentication {
-    pub fn new(pair_code: u32, name: String) -> AdbDeviceAuthentication {
+    pub fn new(pair_code: PairCode, name: String) -> AdbDeviceAuthentication {
         AdbDeviceAuthentication {
-            state: AdbConnectionState::Unpaired(name, pair_code),
+            name_prefix: name.clone(),
+            pair_name: name,
+            conflicts: HashSet::new(),
+            pair_code,
+            state: AdbConnectionState::Unpaired,
+            paired: false,
+            failed: HashSet::new(),
+            observers: Vec::new(),
             known_address: HashMap::new(),
+            known_devices: HashMap::new(),
         }
     }
+
+    /// Warns about the pairing services named `prefix` followed by something
+    /// else than our name, the pairing name is used by default.
+    pub fn with_name_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.name_prefix = prefix.into();
+        self
+    }
+
     pub fn is_connected(&self) -> bool {
-        matches!(self.state, AdbConnectionState::Connected)
+        matches!(self.state, AdbConnectionState::Connected { .. })
+    }
+
+    pub(crate) fn add_observer(&mut self, observer: StateObserver) {
+        self.observers.push(observer);
+    }
+
+    fn transition(&mut self, to: AdbConnectionState) {
+        if self.state == to {
+            return;
+        }
+        let transition = AdbStateTransition {
+            from: std::mem::replace(&mut self.state, to.clone()),
+            to,
+        };
+        debug_assert!(transition.is_valid(), "invalid transition {transition:?}");
+        log::debug!("{:?} -> {:?}", transition.from, transition.to);
+        for observer in &self.observers {
+            (observer.0)(&transition);
+        }
     }
 
     fn connect<C: AdbClient>(&mut self, address: &str, client: &C) {
-        if client.adb_connect(address).is_ok() {
-            self.state = AdbConnectionState::Connected
+        self.start_connecting(address);
+        self.on_connect_result(address, client.adb_connect(address));
+    }
+
+    async fn connect_async<C: AsyncAdbClient>(&mut self, address: &str, client: &C) {
+        self.start_connecting(address);
+        self.on_connect_result(address, client.adb_connect(address).await);
+    }
+
+    fn start_connecting(&mut self, address: &str) {
+        self.transition(AdbConnectionState::Connecting {
+            address: address.to_string(),
+        });
+    }
+
+    fn on_connect_result(&mut self, address: &str, result: Result<(), String>) {
+        match result {
+            Ok(()) => self.transition(AdbConnectionState::Connected {
+                address: address.to_string(),
+            }),
+            Err(reason) => {
+                self.failed.insert(address.to_string());
+                self.transition(AdbConnectionState::Failed { reason })
+            }
         }
     }
+
     fn is_not_local(domain: &str) -> bool {
         domain != "local"
     }
 
     pub fn on_pair<C: AdbClient>(&mut self, service: &AdbService, client: &C) {
-        if let AdbConnectionState::Unpaired(name, pair_code) = &self.state {
-            if !service.name.contains(name) || Self::is_not_local(&service.domain) {
-                log::trace!(
-                    "service has different name or domain, service: {service:?} auth: {self:?}"
+        let Some(address) = self.start_pairing(service) else {
+            return;
+        };
+        let result = client.adb_pair(&address, &self.pair_code);
+        if let Some(address) = self.on_pair_result(service, result) {
+            self.connect(&address, client)
+        }
+    }
+
+    /// Same as [`AdbDeviceAuthentication::on_pair`], with an async client.
+    pub async fn on_pair_async<C: AsyncAdbClient>(&mut self, service: &AdbService, client: &C) {
+        let Some(address) = self.start_pairing(service) else {
+            return;
+        };
+        let result = client.adb_pair(&address, &self.pair_code).await;
+        if let Some(address) = self.on_pair_result(service, result) {
+            self.connect_async(&address, client).await
+        }
+    }
+
+    /// Address to pair with when `service` is the one from our QR code.
+    fn start_pairing(&mut self, service: &AdbService) -> Option<String> {
+        if self.paired || self.is_connected() {
+            return None;
+        }
+        let name = &self.pair_name;
+        if service.instance_name() != name || Self::is_not_local(&service.domain) {
+            let instance_name = service.instance_name();
+            if instance_name != name
+                && instance_name.starts_with(self.name_prefix.as_str())
+                && self.conflicts.insert(instance_name.to_string())
+            {
+                log::warn!(
+                    "pairing service {instance_name} has a name conflicting with {name}, is someone else pairing on this network?"
                 );
-                return;
             }
-            if client.adb_pair(&service.address(), *pair_code).is_ok() {
-                self.state = AdbConnectionState::Paired;
+            log::trace!(
+                "service has different name or domain, service: {service:?} auth: {self:?}"
+            );
+            return None;
+        }
 
-                let address = self.get_address(service.ip());
-                if let Some(address) = address {
-                    self.connect(&address, client)
-                }
-            }
+        let address = service.address();
+        if self.failed.contains(&address) {
+            return None;
+        }
+        self.transition(AdbConnectionState::Pairing {
+            address: address.clone(),
+        });
+        Some(address)
+    }
+
+    /// Address to connect to after pairing, when the device's is known.
+    fn on_pair_result(
+        &mut self,
+        service: &AdbService,
+        result: Result<(), String>,
+    ) -> Option<String> {
+        if let Err(reason) = result {
+            self.failed.insert(service.address());
+            self.transition(AdbConnectionState::Failed { reason });
+            return None;
         }
+        // Connecting failed before because the device wasn't paired
+        self.failed.clear();
+        self.paired = true;
+        self.transition(AdbConnectionState::Paired);
+        self.get_address(service.ip())
     }
 
     pub fn on_connect<C: AdbClient>(&mut self, service: &AdbService, client: &C) {
         if Self::is_not_local(&service.domain) {
             return;
         }
+        if self.learn_address(service) || self.should_reconnect(service) {
+            self.connect(&service.address(), client)
+        }
+    }
 
-        if !self.known_address.contains_key(service.ip()) {
-            self.known_address
-                .insert(service.ip().to_string(), service.address());
+    /// Same as [`AdbDeviceAuthentication::on_connect`], with an async client.
+    pub async fn on_connect_async<C: AsyncAdbClient>(&mut self, service: &AdbService, client: &C) {
+        if Self::is_not_local(&service.domain) {
+            return;
+        }
+        if self.learn_address(service) || self.should_reconnect(service) {
+            self.connect_async(&service.address(), client).await
+        }
+    }
 
-            self.connect(&service.address(), client)
+    /// Whether to connect to the already known `service` once paired.
+    fn should_reconnect(&self, service: &AdbService) -> bool {
+        self.paired && !self.is_connected() && !self.failed.contains(&service.address())
+    }
+
+    /// Remembers the address of a device seen for the first time, `true`
+    /// when it's new.
+    fn learn_address(&mut self, service: &AdbService) -> bool {
+        if self.known_address.contains_key(service.ip()) {
+            return false;
+        }
+        self.on_device_address(service);
+        self.known_address
+            .insert(service.ip().to_string(), service.address());
+        true
+    }
+
+    /// Forgets the previous address of a device that came back with another ip.
+    fn on_device_address(&mut self, service: &AdbService) {
+        let Some(instance) = service.adb_instance_name() else {
+            return;
+        };
+        if let Some(old_ip) = self
+            .known_devices
+            .insert(instance.serial.clone(), service.ip().to_string())
+        {
+            if old_ip != *service.ip() {
+                log::debug!(
+                    "device {} moved from {old_ip} to {}",
+                    instance.serial,
+                    service.ip()
+                );
+                self.known_address.remove(&old_ip);
+            }
         }
+    }
 
-        if let AdbConnectionState::Paired = self.state {
-            self.connect(&service.address(), client);
+    /// Forgets connect addresses that aren't advertised anymore, so a device
+    /// restarting wireless debugging on a new port is connected there.
+    pub fn on_service_event(&mut self, event: &AdbServiceEvent) {
+        if let AdbServiceEvent::Removed(_, service) = event {
+            // Tried again when it's advertised again
+            self.failed.remove(&service.address());
+        }
+        match event {
+            AdbServiceEvent::Removed(AdbServiceType::Connect, service)
+                if self.get_address(service.ip()) == Some(service.address()) =>
+            {
+                log::debug!("forgetting removed connect service {service:?}");
+                self.known_address.remove(service.ip());
+                if let Some(instance) = service.adb_instance_name() {
+                    self.known_devices.remove(&instance.serial);
+                }
+                if self.state
+                    == (AdbConnectionState::Connected {
+                        address: service.address(),
+                    })
+                {
+                    self.transition(AdbConnectionState::Disconnected);
+                }
+            }
+            AdbServiceEvent::Updated(AdbServiceType::Connect, service) => {
+                log::debug!("connect service moved to {}", service.address());
+                self.known_address.remove(service.ip());
+            }
+            _ => {}
         }
     }
 
@@ -97,7 +422,20 @@ impl AdbDeviceAuthentication {
 #[cfg(test)]
 mod tests {
 
-    use crate::{adb_device_authentication::AdbConnectionState, client::AdbClient};
+    use std::{
+        cell::RefCell,
+        collections::HashSet,
+        sync::{Arc, Mutex},
+    };
+
+    use proptest::prelude::*;
+
+    use crate::{
+        adb_device_authentication::{AdbConnectionState, AdbStateTransition, StateObserver},
+        adb_mdns_discovery_service::{AdbServiceEvent, AdbServiceType},
+        client::AdbClient,
+        pair_code::PairCode,
+    };
 
     use super::{AdbDeviceAuthentication, AdbService};
 
@@ -105,34 +443,29 @@ mod tests {
     struct ErrorMock;
 
     impl AdbClient for SuccessMock {
-        fn adb_pair(&self, _address: &str, _code: u32) -> Result<(), ()> {
+        fn adb_pair(&self, _address: &str, _code: &PairCode) -> Result<(), String> {
             Ok(())
         }
 
-        fn adb_connect(&self, _address: &str) -> Result<(), ()> {
+        fn adb_connect(&self, _address: &str) -> Result<(), String> {
             Ok(())
         }
     }
 
     impl AdbClient for ErrorMock {
-        fn adb_pair(&self, _address: &str, _code: u32) -> Result<(), ()> {
-            Err(())
+        fn adb_pair(&self, _address: &str, _code: &PairCode) -> Result<(), String> {
+            Err("mock error".into())
         }
 
-        fn adb_connect(&self, _address: &str) -> Result<(), ()> {
-            Err(())
+        fn adb_connect(&self, _address: &str) -> Result<(), String> {
+            Err("mock error".into())
         }
     }
 
     #[test]
     fn test_device_paired() {
-        let mut auth = AdbDeviceAuthentication::new(10, "test".into());
-        let service = AdbService {
-            domain: "local".into(),
-            ip: "123.123.0.123".into(),
-            name: "android".into(),
-            port: 33001,
-        };
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), "test".into());
+        let service = AdbService::new(AdbServiceType::Connect, "android", "123.123.0.123", 33001);
         auth.on_connect(&service, &SuccessMock);
 
         assert!(auth.is_connected());
@@ -140,13 +473,8 @@ mod tests {
 
     #[test]
     fn test_device_not_paired() {
-        let mut auth = AdbDeviceAuthentication::new(10, "test".into());
-        let service = AdbService {
-            domain: "local".into(),
-            ip: "123.123.0.123".into(),
-            name: "android".into(),
-            port: 33001,
-        };
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), "test".into());
+        let service = AdbService::new(AdbServiceType::Connect, "android", "123.123.0.123", 33001);
         auth.on_connect(&service, &ErrorMock);
 
         assert!(!auth.is_connected());
@@ -154,13 +482,13 @@ mod tests {
 
     #[test]
     fn test_on_connect_before_on_pair() {
-        let mut auth = AdbDeviceAuthentication::new(10, "test".into());
-        let connect_service = AdbService {
-            domain: "local".into(),
-            ip: "123.123.0.123".into(),
-            name: "adb-wg858lj7t959helz-si5LWZ".into(),
-            port: 34003,
-        };
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), "test".into());
+        let connect_service = AdbService::new(
+            AdbServiceType::Connect,
+            "adb-wg858lj7t959helz-si5LWZ",
+            "123.123.0.123",
+            34003,
+        );
         auth.on_connect(&connect_service, &ErrorMock);
 
         assert!(!auth.is_connected());
@@ -170,12 +498,7 @@ mod tests {
         // Should failed because the service's name is different from auth
         assert!(!auth.is_connected());
 
-        let pair_service = AdbService {
-            domain: "local".into(),
-            ip: "123.123.0.123".into(),
-            name: "test".into(),
-            port: 44123,
-        };
+        let pair_service = AdbService::new(AdbServiceType::Pairing, "test", "123.123.0.123", 44123);
 
         auth.on_pair(&pair_service, &SuccessMock);
 
@@ -184,14 +507,15 @@ mod tests {
 
     #[test]
     fn test_on_pair_before_on_connect() {
-        let mut auth = AdbDeviceAuthentication::new(10, "WIFI Android Connect".into());
+        let mut auth =
+            AdbDeviceAuthentication::new(PairCode::random(), "WIFI Android Connect".into());
 
-        let pair_service = AdbService {
-            name: "WIFI Android Connect._adb-tls-pairing._tcp.local.".into(),
-            ip: "192.168.0.197".into(),
-            port: 34317,
-            domain: "local".into(),
-        };
+        let pair_service = AdbService::new(
+            AdbServiceType::Pairing,
+            "WIFI Android Connect._adb-tls-pairing._tcp.local.",
+            "192.168.0.197",
+            34317,
+        );
 
         auth.on_pair(&pair_service, &SuccessMock);
 
@@ -201,14 +525,456 @@ mod tests {
             panic!("Auth should be paired at this time {auth:?}");
         }
 
-        let connect_service = AdbService {
-            name: "adb-wg858lj7t959helz-si5LWZ._adb-tls-pairing._tcp.local.".into(),
-            ip: "192.168.0.197".into(),
+        let connect_service = AdbService::new(
+            AdbServiceType::Connect,
+            "adb-wg858lj7t959helz-si5LWZ._adb-tls-pairing._tcp.local.",
+            "192.168.0.197",
+            34317,
+        );
+
+        auth.on_connect(&connect_service, &SuccessMock);
+        assert!(auth.is_connected());
+    }
+
+    struct RecordingMock(std::cell::RefCell<Vec<String>>);
+
+    impl AdbClient for RecordingMock {
+        fn adb_pair(&self, _address: &str, _code: &PairCode) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn adb_connect(&self, address: &str) -> Result<(), String> {
+            self.0.borrow_mut().push(address.into());
+            Err("mock error".into())
+        }
+    }
+
+    #[test]
+    fn test_removed_connect_service_is_forgotten() {
+        let mut auth =
+            AdbDeviceAuthentication::new(PairCode::random(), "WIFI Android Connect".into());
+        let client = RecordingMock(Default::default());
+        let stale = AdbService::new(
+            AdbServiceType::Connect,
+            "adb-wg858lj7t959helz-si5LWZ",
+            "192.168.0.197",
+            34003,
+        );
+        auth.on_connect(&stale, &client);
+        auth.on_service_event(&AdbServiceEvent::Removed(
+            AdbServiceType::Connect,
+            stale.clone(),
+        ));
+        assert_eq!(auth.get_address("192.168.0.197"), None);
+
+        let restarted = AdbService {
+            port: 41005,
+            ..stale.clone()
+        };
+        auth.on_connect(&restarted, &client);
+
+        let pair_service = AdbService {
+            name: "WIFI Android Connect".into(),
             port: 34317,
-            domain: "local".into(),
+            service_type: AdbServiceType::Pairing,
+            ..stale
         };
+        auth.on_pair(&pair_service, &client);
+
+        assert_eq!(
+            *client.0.borrow(),
+            [
+                "192.168.0.197:34003",
+                "192.168.0.197:41005",
+                "192.168.0.197:41005"
+            ]
+        );
+    }
 
-        auth.on_connect(&connect_service, &SuccessMock);
+    #[test]
+    fn test_updated_connect_service_is_reconnected() {
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), "test".into());
+        let client = RecordingMock(Default::default());
+        let service = AdbService::new(
+            AdbServiceType::Connect,
+            "adb-wg858lj7t959helz-si5LWZ",
+            "192.168.0.197",
+            34003,
+        );
+        auth.on_connect(&service, &client);
+
+        let updated = AdbService {
+            port: 41005,
+            ..service
+        };
+        auth.on_service_event(&AdbServiceEvent::Updated(
+            AdbServiceType::Connect,
+            updated.clone(),
+        ));
+        auth.on_connect(&updated, &client);
+
+        assert_eq!(
+            auth.get_address("192.168.0.197"),
+            Some("192.168.0.197:41005".into())
+        );
+        assert_eq!(
+            *client.0.borrow(),
+            ["192.168.0.197:34003", "192.168.0.197:41005"]
+        );
+    }
+
+    #[test]
+    fn test_device_moved_to_another_ip() {
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), "test".into());
+        let client = RecordingMock(Default::default());
+        let before = AdbService::new(
+            AdbServiceType::Connect,
+            "adb-wg858lj7t959helz-si5LWZ",
+            "192.168.0.197",
+            34003,
+        );
+        auth.on_connect(&before, &client);
+
+        let after = AdbService::new(
+            AdbServiceType::Connect,
+            "adb-wg858lj7t959helz-Qx0Tbn",
+            "192.168.0.42",
+            41005,
+        );
+        auth.on_connect(&after, &client);
+
+        assert_eq!(auth.get_address("192.168.0.197"), None);
+        assert_eq!(
+            auth.known_devices.get("wg858lj7t959helz"),
+            Some(&"192.168.0.42".to_string())
+        );
+    }
+
+    #[test]
+    fn test_on_pair_exact_instance_name() {
+        let mut auth =
+            AdbDeviceAuthentication::new(PairCode::random(), "WIFI Android Connect".into());
+
+        for name in [
+            "WIFI Android Connect (2)._adb-tls-pairing._tcp.local.",
+            "WIFI Android Connect-k3J9aZ._adb-tls-pairing._tcp.local.",
+            "My WIFI Android Connect",
+        ] {
+            let pair_service =
+                AdbService::new(AdbServiceType::Pairing, name, "192.168.0.197", 34317);
+            auth.on_pair(&pair_service, &SuccessMock);
+
+            assert_eq!(
+                auth.state,
+                AdbConnectionState::Unpaired,
+                "{name} should not be paired"
+            );
+        }
+    }
+
+    #[test]
+    fn test_conflicting_pairing_services_by_prefix() {
+        let mut auth =
+            AdbDeviceAuthentication::new(PairCode::random(), "WIFI Android Connect-k3J9aZ".into())
+                .with_name_prefix("WIFI Android Connect");
+
+        for name in [
+            "WIFI Android Connect-Zq81Lm._adb-tls-pairing._tcp.local.",
+            "WIFI Android Connect-Zq81Lm",
+            "WIFI Android Connect-k3J9aZ",
+            "Studio-k3J9aZ",
+        ] {
+            let pair_service =
+                AdbService::new(AdbServiceType::Pairing, name, "192.168.0.197", 34317);
+            auth.on_pair(&pair_service, &ErrorMock);
+        }
+
+        assert_eq!(
+            auth.conflicts,
+            HashSet::from(["WIFI Android Connect-Zq81Lm".to_string()])
+        );
+    }
+
+    const PAIR_NAME: &str = "WIFI Android Connect-k3J9aZ";
+
+    fn pair_service() -> AdbService {
+        AdbService::new(AdbServiceType::Pairing, PAIR_NAME, "192.168.0.197", 34317)
+    }
+
+    fn connect_service() -> AdbService {
+        AdbService::new(
+            AdbServiceType::Connect,
+            "adb-wg858lj7t959helz-si5LWZ",
+            "192.168.0.197",
+            34003,
+        )
+    }
+
+    fn observed(auth: &mut AdbDeviceAuthentication) -> Arc<Mutex<Vec<AdbStateTransition>>> {
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let observed = transitions.clone();
+        auth.add_observer(StateObserver::new(move |transition| {
+            observed.lock().unwrap().push(transition.clone())
+        }));
+        transitions
+    }
+
+    /// A device only accepting connections once paired.
+    #[derive(Default)]
+    struct DeviceMock {
+        pair_fails: bool,
+        pairs: RefCell<usize>,
+        pair_attempts: RefCell<usize>,
+        connect_attempts: RefCell<usize>,
+    }
+
+    impl AdbClient for DeviceMock {
+        fn adb_pair(&self, _address: &str, _code: &PairCode) -> Result<(), String> {
+            *self.pair_attempts.borrow_mut() += 1;
+            if self.pair_fails {
+                return Err("failed: wrong password".into());
+            }
+            *self.pairs.borrow_mut() += 1;
+            Ok(())
+        }
+
+        fn adb_connect(&self, _address: &str) -> Result<(), String> {
+            *self.connect_attempts.borrow_mut() += 1;
+            match *self.pairs.borrow() {
+                0 => Err("failed to authenticate".into()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_transitions_on_connect_before_on_pair() {
+        use AdbConnectionState::*;
+
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), PAIR_NAME.into());
+        let transitions = observed(&mut auth);
+        let client = DeviceMock::default();
+
+        auth.on_connect(&connect_service(), &client);
+        auth.on_pair(&pair_service(), &client);
+
+        let connecting = Connecting {
+            address: "192.168.0.197:34003".into(),
+        };
+        let failed = Failed {
+            reason: "failed to authenticate".into(),
+        };
+        let pairing = Pairing {
+            address: "192.168.0.197:34317".into(),
+        };
+        let connected = Connected {
+            address: "192.168.0.197:34003".into(),
+        };
+        let expected: Vec<_> = [
+            (Unpaired, connecting.clone()),
+            (connecting.clone(), failed.clone()),
+            (failed, pairing.clone()),
+            (pairing, Paired),
+            (Paired, connecting.clone()),
+            (connecting, connected),
+        ]
+        .into_iter()
+        .map(|(from, to)| AdbStateTransition { from, to })
+        .collect();
+        assert_eq!(*transitions.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_disconnected_when_connect_service_removed() {
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), PAIR_NAME.into());
+        auth.on_pair(&pair_service(), &SuccessMock);
+        auth.on_connect(&connect_service(), &SuccessMock);
+        assert!(auth.is_connected());
+
+        auth.on_service_event(&AdbServiceEvent::Removed(
+            AdbServiceType::Connect,
+            connect_service(),
+        ));
+        assert_eq!(auth.state, AdbConnectionState::Disconnected);
+
+        auth.on_connect(&connect_service(), &SuccessMock);
         assert!(auth.is_connected());
     }
+
+    #[test]
+    fn test_failed_services_tried_again_once_advertised_again() {
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), PAIR_NAME.into());
+        let client = DeviceMock {
+            pair_fails: true,
+            ..Default::default()
+        };
+
+        // Polled like `WifiAndroidConnect::connect` does
+        for _ in 0..5 {
+            auth.on_pair(&pair_service(), &client);
+            auth.on_connect(&connect_service(), &client);
+        }
+        assert_eq!(*client.pair_attempts.borrow(), 1, "wrong code");
+        assert_eq!(*client.connect_attempts.borrow(), 1, "not paired");
+
+        auth.on_service_event(&AdbServiceEvent::Removed(
+            AdbServiceType::Pairing,
+            pair_service(),
+        ));
+        auth.on_pair(&pair_service(), &client);
+        auth.on_pair(&pair_service(), &client);
+        assert_eq!(*client.pair_attempts.borrow(), 2);
+    }
+
+    #[test]
+    fn test_failed_connect_not_repeated_when_paired() {
+        let mut auth = AdbDeviceAuthentication::new(PairCode::random(), PAIR_NAME.into());
+        let client = RecordingMock(Default::default());
+        auth.on_pair(&pair_service(), &client);
+
+        auth.on_connect(&connect_service(), &client);
+        auth.on_connect(&connect_service(), &client);
+        assert_eq!(*client.0.borrow(), ["192.168.0.197:34003"]);
+
+        let restarted = AdbService {
+            port: 41005,
+            ..connect_service()
+        };
+        auth.on_service_event(&AdbServiceEvent::Updated(
+            AdbServiceType::Connect,
+            restarted.clone(),
+        ));
+        auth.on_connect(&restarted, &client);
+        assert_eq!(
+            *client.0.borrow(),
+            ["192.168.0.197:34003", "192.168.0.197:41005"]
+        );
+    }
+
+    #[derive(Debug, Clone)]
+    enum Arrival {
+        Pairing,
+        Connect,
+        /// Someone else's pairing service.
+        OtherPairing,
+        ConnectRemoved,
+        /// Hands the advertised services to the authentication, like
+        /// `WifiAndroidConnect::iter`.
+        Round,
+    }
+
+    fn arrival() -> impl Strategy<Value = Arrival> {
+        prop_oneof![
+            Just(Arrival::Pairing),
+            Just(Arrival::Connect),
+            Just(Arrival::OtherPairing),
+            Just(Arrival::ConnectRemoved),
+            Just(Arrival::Round),
+        ]
+    }
+
+    /// Plays `arrivals`, then a last round with the pairing and connect
+    /// services advertised, returning the observed transitions.
+    fn play(
+        auth: &mut AdbDeviceAuthentication,
+        client: &DeviceMock,
+        arrivals: Vec<Arrival>,
+    ) -> Vec<AdbStateTransition> {
+        let transitions = observed(auth);
+        let other = AdbService::new(
+            AdbServiceType::Pairing,
+            "WIFI Android Connect-Zx81Qa",
+            "192.168.0.42",
+            37011,
+        );
+        let mut pairing = vec![];
+        let mut connect = None;
+
+        let round = |auth: &mut AdbDeviceAuthentication,
+                     pairing: &[AdbService],
+                     connect: Option<&AdbService>| {
+            for service in pairing {
+                auth.on_pair(service, client);
+            }
+            if let Some(service) = connect {
+                auth.on_connect(service, client);
+            }
+        };
+
+        for arrival in arrivals {
+            match arrival {
+                Arrival::Pairing => pairing.push(pair_service()),
+                Arrival::OtherPairing => pairing.push(other.clone()),
+                Arrival::Connect => connect = Some(connect_service()),
+                Arrival::ConnectRemoved => {
+                    if let Some(service) = connect.take() {
+                        auth.on_service_event(&AdbServiceEvent::Removed(
+                            AdbServiceType::Connect,
+                            service,
+                        ));
+                    }
+                }
+                Arrival::Round => round(auth, &pairing, connect.as_ref()),
+            }
+        }
+        round(auth, &[other, pair_service()], Some(&connect_service()));
+
+        let transitions = transitions.lock().unwrap().clone();
+        transitions
+    }
+
+    fn assert_consistent(transitions: &[AdbStateTransition]) -> Result<(), TestCaseError> {
+        for transition in transitions {
+            prop_assert!(transition.is_valid(), "invalid {:?}", transition);
+        }
+        for pair in transitions.windows(2) {
+            prop_assert_eq!(&pair[0].to, &pair[1].from);
+        }
+        if let Some(first) = transitions.first() {
+            prop_assert_eq!(&first.from, &AdbConnectionState::Unpaired);
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_connects_whatever_the_arrival_order(
+            arrivals in proptest::collection::vec(arrival(), 0..24)
+        ) {
+            let mut auth = AdbDeviceAuthentication::new(PairCode::random(), PAIR_NAME.into());
+            let client = DeviceMock::default();
+
+            let transitions = play(&mut auth, &client, arrivals);
+
+            prop_assert!(auth.is_connected(), "{:?}", auth.state);
+            prop_assert_eq!(*client.pairs.borrow(), 1);
+            assert_consistent(&transitions)?;
+        }
+
+        #[test]
+        fn prop_never_connects_when_pairing_fails(
+            arrivals in proptest::collection::vec(arrival(), 0..24)
+        ) {
+            let mut auth = AdbDeviceAuthentication::new(PairCode::random(), PAIR_NAME.into());
+            let client = DeviceMock { pair_fails: true, ..Default::default() };
+
+            let transitions = play(&mut auth, &client, arrivals);
+
+            prop_assert!(
+                matches!(auth.state, AdbConnectionState::Failed { .. }),
+                "{:?}",
+                auth.state
+            );
+            let paired = transitions.iter().any(|t| {
+                matches!(
+                    t.to,
+                    AdbConnectionState::Paired | AdbConnectionState::Connected { .. }
+                )
+            });
+            prop_assert!(!paired, "{:?}", transitions);
+            // The pairing service is never withdrawn, so never tried again
+            prop_assert_eq!(*client.pair_attempts.borrow(), 1);
+            assert_consistent(&transitions)?;
+        }
+    }
 }
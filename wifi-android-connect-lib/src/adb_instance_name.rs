@@ -0,0 +1,101 @@
+use std::{fmt, str::FromStr};
+
+const ADB_PREFIX: &str = "adb-";
+
+/// Strips the `._adb-tls-*._tcp.local.` suffix some backends append to the
+/// instance name.
+pub(crate) fn strip_service_type(name: &str) -> &str {
+    match name.split_once("._adb-tls-") {
+        Some((instance, _service_type)) => instance,
+        None => name,
+    }
+}
+
+/// The instance name adbd advertises its connect service with,
+/// `adb-<serial>-<suffix>`, e.g. `adb-wg858lj7t959helz-si5LWZ`.
+///
+/// The serial identifies the device, the suffix is picked randomly each time
+/// wireless debugging is turned on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AdbInstanceName {
+    pub serial: String,
+    pub suffix: String,
+}
+
+impl FromStr for AdbInstanceName {
+    type Err = String;
+
+    /// Parses an instance name, with or without the `._adb-tls-*._tcp.local.`
+    /// service type suffix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(name) = strip_service_type(s).strip_prefix(ADB_PREFIX) else {
+            return Err(format!("{s} isn't an adb instance name"));
+        };
+        // Serials may contain dashes, the suffix doesn't
+        match name.rsplit_once('-') {
+            Some((serial, suffix)) if !serial.is_empty() && !suffix.is_empty() => {
+                Ok(AdbInstanceName {
+                    serial: serial.into(),
+                    suffix: suffix.into(),
+                })
+            }
+            _ => Err(format!("{s} isn't an adb instance name")),
+        }
+    }
+}
+
+impl fmt::Display for AdbInstanceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{ADB_PREFIX}{}-{}", self.serial, self.suffix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(serial: &str, suffix: &str) -> AdbInstanceName {
+        AdbInstanceName {
+            serial: serial.into(),
+            suffix: suffix.into(),
+        }
+    }
+
+    #[test]
+    fn test_parse_instance_name() {
+        assert_eq!(
+            "adb-wg858lj7t959helz-si5LWZ".parse(),
+            Ok(instance("wg858lj7t959helz", "si5LWZ"))
+        );
+        assert_eq!(
+            "adb-wg858lj7t959helz-si5LWZ._adb-tls-pairing._tcp.local.".parse(),
+            Ok(instance("wg858lj7t959helz", "si5LWZ"))
+        );
+        assert_eq!(
+            "adb-emulator-5554-Ab12Cd".parse(),
+            Ok(instance("emulator-5554", "Ab12Cd"))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_instance_name() {
+        for name in [
+            "",
+            "android",
+            "test",
+            "WIFI Android Connect._adb-tls-pairing._tcp.local.",
+            "adb-wg858lj7t959helz",
+            "adb--si5LWZ",
+            "adb-wg858lj7t959helz-",
+        ] {
+            assert!(name.parse::<AdbInstanceName>().is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let name = instance("wg858lj7t959helz", "si5LWZ");
+        assert_eq!(name.to_string(), "adb-wg858lj7t959helz-si5LWZ");
+        assert_eq!(name.to_string().parse(), Ok(name));
+    }
+}
@@ -1,10 +1,290 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+};
 
 use crate::adb_device_authentication::AdbService;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
+pub enum AdbServiceType {
+    /// `_adb-tls-pairing._tcp`, advertised while the pairing dialog is open.
+    Pairing,
+    /// `_adb-tls-connect._tcp`, advertised while wireless debugging is on.
+    Connect,
+}
+
+impl AdbServiceType {
+    pub const ALL: [AdbServiceType; 2] = [AdbServiceType::Pairing, AdbServiceType::Connect];
+
+    /// The service type without domain, e.g. `_adb-tls-pairing._tcp`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdbServiceType::Pairing => "_adb-tls-pairing._tcp",
+            AdbServiceType::Connect => "_adb-tls-connect._tcp",
+        }
+    }
+
+    /// Parses a service type, with or without the `.local.` domain.
+    pub fn from_service_type(service_type: &str) -> Option<AdbServiceType> {
+        let service_type = service_type.trim_end_matches('.');
+        let service_type = service_type.strip_suffix(".local").unwrap_or(service_type);
+        Self::ALL.into_iter().find(|t| t.as_str() == service_type)
+    }
+}
+
+/// A change in the services advertised on the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
+pub enum AdbServiceEvent {
+    Added(AdbServiceType, AdbService),
+    /// A known service was resolved with a different address.
+    Updated(AdbServiceType, AdbService),
+    /// The service isn't advertised anymore, carrying its last known address.
+    Removed(AdbServiceType, AdbService),
+}
+
+/// Browses the `_adb-tls-pairing._tcp` and `_adb-tls-connect._tcp` mDNS services.
 pub trait AdbMDnsDiscoveryService {
     fn start(&self) -> Result<(), String>;
     fn stop(&self) -> Result<(), String>;
+    /// Pairing services currently advertised.
     fn adb_tls_pairing(&self) -> HashSet<AdbService>;
+    /// Connect services currently advertised.
     fn adb_tls_connect(&self) -> HashSet<AdbService>;
+    /// Changes seen since the last call, oldest first.
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        Vec::new()
+    }
+}
+
+/// Same as [`AdbMDnsDiscoveryService`], without blocking the async runtime.
+pub trait AsyncAdbMDnsDiscoveryService {
+    fn start(&self) -> impl Future<Output = Result<(), String>> + Send;
+    fn stop(&self) -> impl Future<Output = Result<(), String>> + Send;
+    /// Pairing services currently advertised.
+    fn adb_tls_pairing(&self) -> HashSet<AdbService>;
+    /// Connect services currently advertised.
+    fn adb_tls_connect(&self) -> HashSet<AdbService>;
+    /// Waits for the next change, `None` once discovery is stopped.
+    fn next_event(&self) -> impl Future<Output = Option<AdbServiceEvent>> + Send;
+}
+
+/// Services currently advertised, keyed by name, and the changes not yet
+/// consumed through [`AdbMDnsDiscoveryService::events`].
+#[derive(Debug, Default)]
+pub(crate) struct ServiceRegistry {
+    services: HashMap<(AdbServiceType, String), AdbService>,
+    events: Vec<AdbServiceEvent>,
+}
+
+impl ServiceRegistry {
+    pub fn resolved(&mut self, service_type: AdbServiceType, service: AdbService) {
+        if service_type == AdbServiceType::Connect {
+            self.remove_previous_instances(&service);
+        }
+
+        let key = (service_type, service.name.clone());
+        match self.services.insert(key, service.clone()) {
+            None => self
+                .events
+                .push(AdbServiceEvent::Added(service_type, service)),
+            Some(old) if old != service => self
+                .events
+                .push(AdbServiceEvent::Updated(service_type, service)),
+            Some(_) => {}
+        }
+    }
+
+    pub fn removed(&mut self, service_type: AdbServiceType, name: &str) {
+        if let Some(service) = self.services.remove(&(service_type, name.to_string())) {
+            self.events
+                .push(AdbServiceEvent::Removed(service_type, service));
+        }
+    }
+
+    /// A device turning wireless debugging off and on again advertises a new
+    /// instance, the previous one may linger until its record expires.
+    fn remove_previous_instances(&mut self, service: &AdbService) {
+        let Some(instance) = service.adb_instance_name() else {
+            return;
+        };
+        let previous: Vec<String> = self
+            .services
+            .iter()
+            .filter(|((t, name), other)| {
+                *t == AdbServiceType::Connect
+                    && *name != service.name
+                    && other.adb_instance_name().map(|i| i.serial) == Some(instance.serial.clone())
+            })
+            .map(|((_, name), _)| name.clone())
+            .collect();
+
+        for name in previous {
+            log::debug!("{name} replaced by {}", service.name);
+            self.removed(AdbServiceType::Connect, &name);
+        }
+    }
+
+    /// Replaces the services of `service_type` with `services`.
+    pub fn sync(&mut self, service_type: AdbServiceType, services: HashSet<AdbService>) {
+        let names: HashSet<&String> = services.iter().map(|s| &s.name).collect();
+        let gone: Vec<String> = self
+            .services
+            .keys()
+            .filter(|(t, name)| *t == service_type && !names.contains(name))
+            .map(|(_, name)| name.clone())
+            .collect();
+
+        for name in gone {
+            self.removed(service_type, &name);
+        }
+        for service in services {
+            self.resolved(service_type, service);
+        }
+    }
+
+    pub fn services(&self, service_type: AdbServiceType) -> HashSet<AdbService> {
+        self.services
+            .iter()
+            .filter(|((t, _), _)| *t == service_type)
+            .map(|(_, service)| service.clone())
+            .collect()
+    }
+
+    pub fn take_events(&mut self) -> Vec<AdbServiceEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// The oldest change not yet consumed.
+    pub fn next_event(&mut self) -> Option<AdbServiceEvent> {
+        (!self.events.is_empty()).then(|| self.events.remove(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(service_type: AdbServiceType, name: &str, port: u16) -> AdbService {
+        AdbService::new(service_type, name, "192.168.0.197", port)
+    }
+
+    #[test]
+    fn test_parse_service_type() {
+        for service_type in ["_adb-tls-connect._tcp", "_adb-tls-connect._tcp.local."] {
+            assert_eq!(
+                AdbServiceType::from_service_type(service_type),
+                Some(AdbServiceType::Connect)
+            );
+        }
+        assert_eq!(AdbServiceType::from_service_type("_adb._tcp"), None);
+    }
+
+    #[test]
+    fn test_registry_events() {
+        let connect = AdbServiceType::Connect;
+        let mut registry = ServiceRegistry::default();
+
+        registry.resolved(
+            connect,
+            service(connect, "adb-wg858lj7t959helz-si5LWZ", 34003),
+        );
+        registry.resolved(
+            connect,
+            service(connect, "adb-wg858lj7t959helz-si5LWZ", 34003),
+        );
+        registry.resolved(
+            connect,
+            service(connect, "adb-wg858lj7t959helz-si5LWZ", 41005),
+        );
+        registry.removed(connect, "adb-wg858lj7t959helz-si5LWZ");
+        registry.removed(connect, "adb-wg858lj7t959helz-si5LWZ");
+
+        assert_eq!(
+            registry.take_events(),
+            vec![
+                AdbServiceEvent::Added(
+                    connect,
+                    service(connect, "adb-wg858lj7t959helz-si5LWZ", 34003)
+                ),
+                AdbServiceEvent::Updated(
+                    connect,
+                    service(connect, "adb-wg858lj7t959helz-si5LWZ", 41005)
+                ),
+                AdbServiceEvent::Removed(
+                    connect,
+                    service(connect, "adb-wg858lj7t959helz-si5LWZ", 41005)
+                ),
+            ]
+        );
+        assert!(registry.take_events().is_empty());
+        assert!(registry.services(connect).is_empty());
+    }
+
+    #[test]
+    fn test_registry_sync() {
+        let pairing = AdbServiceType::Pairing;
+        let mut registry = ServiceRegistry::default();
+        registry.resolved(pairing, service(pairing, "studio-k3J9aZ", 34317));
+        registry.resolved(
+            AdbServiceType::Connect,
+            service(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            ),
+        );
+        registry.take_events();
+
+        registry.sync(
+            pairing,
+            HashSet::from([service(pairing, "studio-Zx81Qa", 37011)]),
+        );
+
+        assert_eq!(
+            registry.take_events(),
+            vec![
+                AdbServiceEvent::Removed(pairing, service(pairing, "studio-k3J9aZ", 34317)),
+                AdbServiceEvent::Added(pairing, service(pairing, "studio-Zx81Qa", 37011)),
+            ]
+        );
+        assert_eq!(
+            registry.services(pairing),
+            HashSet::from([service(pairing, "studio-Zx81Qa", 37011)])
+        );
+        assert_eq!(registry.services(AdbServiceType::Connect).len(), 1);
+    }
+
+    #[test]
+    fn test_registry_replaces_previous_instance() {
+        let connect = AdbServiceType::Connect;
+        let mut registry = ServiceRegistry::default();
+        registry.resolved(
+            connect,
+            service(connect, "adb-wg858lj7t959helz-si5LWZ", 34003),
+        );
+        registry.resolved(connect, service(connect, "adb-R58M123ABCD-k3J9aZ", 38211));
+        registry.take_events();
+
+        registry.resolved(
+            connect,
+            service(connect, "adb-wg858lj7t959helz-Qx0Tbn", 41005),
+        );
+
+        assert_eq!(
+            registry.take_events(),
+            vec![
+                AdbServiceEvent::Removed(
+                    connect,
+                    service(connect, "adb-wg858lj7t959helz-si5LWZ", 34003)
+                ),
+                AdbServiceEvent::Added(
+                    connect,
+                    service(connect, "adb-wg858lj7t959helz-Qx0Tbn", 41005)
+                ),
+            ]
+        );
+        assert_eq!(registry.services(connect).len(), 2);
+    }
 }
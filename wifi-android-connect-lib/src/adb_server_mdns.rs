@@ -0,0 +1,231 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddrV4,
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    adb_device_authentication::AdbService,
+    adb_mdns_discovery_service::{
+        AdbMDnsDiscoveryService, AdbServiceEvent, AdbServiceType, ServiceRegistry,
+    },
+    client::{adb_host_request, adb_server_address},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct PollSession {
+    stop: mpsc::Sender<()>,
+    worker: JoinHandle<()>,
+}
+
+/// Discovery backend asking the adb server for the mDNS services it has seen
+/// (`adb mdns services`), for when this process can't use multicast itself.
+pub struct AdbServerMdns {
+    server: SocketAddrV4,
+    session: Mutex<Option<PollSession>>,
+    registry: Arc<Mutex<ServiceRegistry>>,
+}
+
+/// Parses the `host:mdns:services` response, one `name\ttype\taddress:port` per line.
+fn parse_mdns_services(response: &str) -> (HashSet<AdbService>, HashSet<AdbService>) {
+    let mut pairing = HashSet::new();
+    let mut connect = HashSet::new();
+
+    for line in response.lines().filter(|line| !line.trim().is_empty()) {
+        let mut fields = line.split('\t');
+        let (Some(name), Some(service_type), Some(address)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            log::warn!("Unexpected adb mdns service line: {line}");
+            continue;
+        };
+
+        let Some((ip, port)) = address.trim().rsplit_once(':') else {
+            log::warn!("Unexpected adb mdns service address: {address}");
+            continue;
+        };
+        let Ok(port) = port.parse() else {
+            log::warn!("Unexpected adb mdns service port: {address}");
+            continue;
+        };
+
+        let Some(adb_service_type) = AdbServiceType::from_service_type(service_type) else {
+            log::trace!("ignoring adb mdns service type {service_type}");
+            continue;
+        };
+        let service = AdbService::new(adb_service_type, name, ip, port);
+        match adb_service_type {
+            AdbServiceType::Pairing => pairing.insert(service),
+            AdbServiceType::Connect => connect.insert(service),
+        };
+    }
+
+    (pairing, connect)
+}
+
+fn poll(server: SocketAddrV4, registry: &Mutex<ServiceRegistry>) -> Result<(), String> {
+    let response = adb_host_request(server, "host:mdns:services")?;
+    let (pairing, connect) = parse_mdns_services(&response);
+
+    let mut registry = registry.lock().unwrap();
+    registry.sync(AdbServiceType::Pairing, pairing);
+    registry.sync(AdbServiceType::Connect, connect);
+    Ok(())
+}
+
+impl AdbMDnsDiscoveryService for AdbServerMdns {
+    fn start(&self) -> Result<(), String> {
+        let mut session = self.session.lock().unwrap();
+        if session.is_some() {
+            return Err("adb server discovery already started".into());
+        }
+
+        // Fail early if the adb server can't be reached, so a fallback can be tried.
+        poll(self.server, &self.registry)?;
+
+        let (stop, stop_rx) = mpsc::channel();
+        let server = self.server;
+        let registry = self.registry.clone();
+        let worker = std::thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(POLL_INTERVAL) {
+                if let Err(e) = poll(server, &registry) {
+                    log::warn!("Failed to poll adb server mdns services: {e}");
+                }
+            }
+        });
+
+        *session = Some(PollSession { stop, worker });
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), String> {
+        if let Some(session) = self.session.lock().unwrap().take() {
+            let _ = session.stop.send(());
+            session
+                .worker
+                .join()
+                .map_err(|_| "adb server discovery worker panicked".to_string())?;
+        }
+        Ok(())
+    }
+
+    fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Pairing)
+    }
+
+    fn adb_tls_connect(&self) -> HashSet<AdbService> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Connect)
+    }
+
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        self.registry.lock().unwrap().take_events()
+    }
+}
+
+impl AdbServerMdns {
+    /// Asks the adb server on `127.0.0.1:5037`, or on `ADB_SERVER_SOCKET`
+    /// when it's set.
+    pub fn new() -> AdbServerMdns {
+        Self::with_server(adb_server_address())
+    }
+
+    pub fn with_server(server: SocketAddrV4) -> AdbServerMdns {
+        AdbServerMdns {
+            server,
+            session: Default::default(),
+            registry: Default::default(),
+        }
+    }
+}
+
+impl Default for AdbServerMdns {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(service_type: AdbServiceType, name: &str, ip: &str, port: u16) -> AdbService {
+        AdbService::new(service_type, name, ip, port)
+    }
+
+    #[test]
+    fn test_parse_mdns_services() {
+        let response = "adb-wg858lj7t959helz-si5LWZ\t_adb-tls-connect._tcp\t192.168.0.197:34003\n\
+                        studio-k3J9aZ\t_adb-tls-pairing._tcp.\t192.168.0.197:34317\n\
+                        adb-wg858lj7t959helz\t_adb._tcp\t192.168.0.197:5555\n";
+
+        let (pairing, connect) = parse_mdns_services(response);
+
+        assert_eq!(
+            pairing,
+            HashSet::from([service(
+                AdbServiceType::Pairing,
+                "studio-k3J9aZ",
+                "192.168.0.197",
+                34317
+            )])
+        );
+        assert_eq!(
+            connect,
+            HashSet::from([service(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                "192.168.0.197",
+                34003
+            )])
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_mdns_services() {
+        let response = "\n\
+                        missing-address\t_adb-tls-connect._tcp\n\
+                        bad-port\t_adb-tls-connect._tcp\t192.168.0.197:port\n\
+                        no-port\t_adb-tls-connect._tcp\t192.168.0.197\n";
+
+        let (pairing, connect) = parse_mdns_services(response);
+
+        assert!(pairing.is_empty());
+        assert!(connect.is_empty());
+    }
+
+    #[test]
+    fn test_discover_from_adb_server() {
+        use crate::test_support::{AdbReply, FakeAdbServer};
+
+        let server = FakeAdbServer::start().unwrap();
+        server.reply(
+            "host:mdns:services",
+            AdbReply::Okay("studio-k3J9aZ\t_adb-tls-pairing._tcp\t192.168.0.197:34317\n".into()),
+        );
+        let mdns = AdbServerMdns::with_server(server.address());
+
+        mdns.start().unwrap();
+        let pairing = mdns.adb_tls_pairing();
+        mdns.stop().unwrap();
+
+        assert_eq!(
+            pairing,
+            HashSet::from([service(
+                AdbServiceType::Pairing,
+                "studio-k3J9aZ",
+                "192.168.0.197",
+                34317
+            )])
+        );
+        assert!(mdns.adb_tls_connect().is_empty());
+    }
+}
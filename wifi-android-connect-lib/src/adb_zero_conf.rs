@@ -1,22 +1,32 @@
-use std::cell::RefCell;
 use std::collections::HashSet;
-use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
 use std::time::Duration;
-use zeroconf::prelude::{TEventLoop, TMdnsBrowser};
-use zeroconf::{EventLoop, MdnsBrowser, ServiceDiscovery, ServiceType};
+use zeroconf::prelude::{TEventLoop, TMdnsBrowser, TTxtRecord};
+use zeroconf::{MdnsBrowser, ServiceDiscovery, ServiceType};
 
 use crate::adb_device_authentication::AdbService;
-use crate::adb_mdns_discovery_service::AdbMDnsDiscoveryService;
-
+use crate::adb_mdns_discovery_service::{
+    AdbMDnsDiscoveryService, AdbServiceEvent, AdbServiceType, ServiceRegistry,
+};
+
+/// Discovery backend using the system's mDNS daemon (Avahi on Linux, Bonjour on macOS).
+///
+/// zeroconf's browsers aren't `Send`, so they live in a worker thread that
+/// polls them until `stop` is called. zeroconf doesn't report removed
+/// services, so services are only added or updated.
 pub struct AdbZeroConf {
-    // Browsers can't dropout until the connection is finish
-    browsers: RefCell<Option<[(MdnsBrowser, EventLoop); 2]>>,
-    pair_set: Rc<RefCell<HashSet<AdbService>>>,
-    connect_set: Rc<RefCell<HashSet<AdbService>>>,
+    running: Arc<AtomicBool>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    registry: Arc<Mutex<ServiceRegistry>>,
 }
 
-fn zero_conf_filter_service(service: zeroconf::Result<ServiceDiscovery>) -> Option<AdbService> {
+fn zero_conf_filter_service(
+    service_type: AdbServiceType,
+    service: zeroconf::Result<ServiceDiscovery>,
+) -> Option<AdbService> {
     let service = match service {
         Ok(s) => s,
         Err(e) => {
@@ -30,84 +40,140 @@ fn zero_conf_filter_service(service: zeroconf::Result<ServiceDiscovery>) -> Opti
     log::info!("service: address {}", service.address());
     log::trace!("Service domain: {}", service.domain());
 
-    Some(AdbService::from(service))
+    Some(adb_service(service_type, service))
 }
 
-impl AdbMDnsDiscoveryService for AdbZeroConf {
-    fn start(&self) -> Result<(), String> {
-        let mut pair_b = MdnsBrowser::new(ServiceType::from_str("_adb-tls-pairing._tcp").unwrap());
-        let mut connect_b =
-            MdnsBrowser::new(ServiceType::from_str("_adb-tls-connect._tcp").unwrap());
+fn browse(
+    registry: Arc<Mutex<ServiceRegistry>>,
+    running: Arc<AtomicBool>,
+    started: mpsc::Sender<Result<(), String>>,
+) {
+    let mut browsers = Vec::with_capacity(AdbServiceType::ALL.len());
+    for adb_service_type in AdbServiceType::ALL {
+        let service_type = match ServiceType::from_str(adb_service_type.as_str()) {
+            Ok(service_type) => service_type,
+            Err(e) => {
+                let _ = started.send(Err(e.to_string()));
+                return;
+            }
+        };
+        let mut browser = MdnsBrowser::new(service_type);
+        let registry = registry.clone();
+        browser.set_service_discovered_callback(Box::new(move |zero_s, _c| {
+            if let Some(s) = zero_conf_filter_service(adb_service_type, zero_s) {
+                registry.lock().unwrap().resolved(adb_service_type, s);
+            }
+        }));
+        browsers.push(browser);
+    }
 
-        let mut browsers = [&mut pair_b, &mut connect_b];
+    // Browsers can't dropout until the connection is finish
+    let mut event_loops = Vec::with_capacity(browsers.len());
+    for browser in &mut browsers {
+        match browser.browse_services() {
+            Ok(event_loop) => event_loops.push(event_loop),
+            Err(e) => {
+                let _ = started.send(Err(e.to_string()));
+                return;
+            }
+        }
+    }
+    let _ = started.send(Ok(()));
+
+    while running.load(Ordering::Acquire) {
+        for event_loop in &event_loops {
+            if let Err(e) = event_loop.poll(Duration::from_millis(100)) {
+                log::error!("Error polling zeroconf event loop: {e}");
+            }
+        }
+    }
+}
 
-        let sets = [self.pair_set.clone(), self.connect_set.clone()];
+impl AdbMDnsDiscoveryService for AdbZeroConf {
+    fn start(&self) -> Result<(), String> {
+        let mut worker = self.worker.lock().unwrap();
+        if worker.is_some() {
+            return Err("zeroconf discovery already started".into());
+        }
 
-        browsers.iter_mut().zip(sets).for_each(|(browser, set)| {
-            browser.set_service_discovered_callback(Box::new(move |zero_s, _c| {
-                if let Some(s) = zero_conf_filter_service(zero_s) {
-                    let mut a = (*set).borrow_mut();
-                    a.insert(s);
-                }
-            }));
-        });
+        let registry = self.registry.clone();
+        let running = self.running.clone();
+        let (tx, rx) = mpsc::channel();
 
-        let events = (
-            browsers[0].browse_services().map_err(|e| e.to_string())?,
-            browsers[1].browse_services().map_err(|e| e.to_string())?,
-        );
+        running.store(true, Ordering::Release);
+        let handle = std::thread::spawn(move || browse(registry, running, tx));
 
-        *self.browsers.borrow_mut() = Some([(pair_b, events.0), (connect_b, events.1)]);
+        let started = rx
+            .recv()
+            .unwrap_or_else(|_| Err("zeroconf worker exited before starting".into()));
+        if started.is_err() {
+            self.running.store(false, Ordering::Release);
+            let _ = handle.join();
+            return started;
+        }
 
+        *worker = Some(handle);
         Ok(())
     }
 
     fn stop(&self) -> Result<(), String> {
-        *self.browsers.borrow_mut() = None;
+        self.running.store(false, Ordering::Release);
+        if let Some(handle) = self.worker.lock().unwrap().take() {
+            handle
+                .join()
+                .map_err(|_| "zeroconf worker panicked".to_string())?;
+        }
         Ok(())
     }
 
-    fn adb_tls_pairing(&self) -> std::collections::HashSet<AdbService> {
-        self.poll().unwrap();
-        let set = (*self.pair_set).borrow_mut().clone();
-
-        set
+    fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Pairing)
     }
 
-    fn adb_tls_connect(&self) -> std::collections::HashSet<AdbService> {
-        self.poll().unwrap();
-        let set = (*self.connect_set).borrow_mut().clone();
+    fn adb_tls_connect(&self) -> HashSet<AdbService> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Connect)
+    }
 
-        set
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        self.registry.lock().unwrap().take_events()
     }
 }
 
 impl AdbZeroConf {
     pub fn new() -> AdbZeroConf {
         AdbZeroConf {
-            browsers: Default::default(),
-            pair_set: Default::default(),
-            connect_set: Default::default(),
+            running: Default::default(),
+            worker: Default::default(),
+            registry: Default::default(),
         }
     }
+}
 
-    pub fn poll(&self) -> Result<(), zeroconf::error::Error> {
-        if let Some(browsers) = &*self.browsers.borrow_mut() {
-            for (_, e) in browsers {
-                e.poll(Duration::from_secs(1))?
-            }
-        }
-        Ok(())
+impl Default for AdbZeroConf {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl From<ServiceDiscovery> for AdbService {
-    fn from(value: ServiceDiscovery) -> Self {
-        AdbService {
-            name: value.name().into(),
-            ip: value.address().into(),
-            port: *value.port(),
-            domain: value.domain().into(),
-        }
+fn adb_service(service_type: AdbServiceType, value: ServiceDiscovery) -> AdbService {
+    AdbService {
+        name: value.name().into(),
+        ip: value.address().into(),
+        port: *value.port(),
+        domain: value.domain().into(),
+        service_type,
+        hostname: value.host_name().into(),
+        addresses: vec![value.address().into()],
+        txt: value
+            .txt()
+            .as_ref()
+            .map(|txt| txt.iter().collect())
+            .unwrap_or_default(),
     }
 }
@@ -1,95 +1,428 @@
 use std::{
     collections::HashSet,
-    sync::{Arc, Mutex},
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use mdns_sd::{ServiceDaemon, ServiceEvent};
+use mdns_sd::{DaemonStatus, Receiver, ServiceDaemon, ServiceEvent};
 
 use crate::{
-    adb_device_authentication::AdbService, adb_mdns_discovery_service::AdbMDnsDiscoveryService,
+    adb_device_authentication::AdbService,
+    adb_mdns_discovery_service::{
+        self, AdbMDnsDiscoveryService, AdbServiceEvent, AdbServiceType, ServiceRegistry,
+    },
+    interface_filter::InterfaceFilter,
 };
 
-pub struct AdbMdns {
+struct BrowseSession {
     demon: ServiceDaemon,
-    pair_set: Arc<Mutex<HashSet<AdbService>>>,
-    connect_set: Arc<Mutex<HashSet<AdbService>>>,
+    running: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+    /// Receivers read by `next_event` instead of workers, when started as an
+    /// [`adb_mdns_discovery_service::AsyncAdbMDnsDiscoveryService`].
+    receivers: Vec<(AdbServiceType, Receiver<ServiceEvent>)>,
+}
+
+pub struct AdbMdns {
+    /// Daemon created ahead of `start`, so creation errors are reported early.
+    demon: Mutex<Option<ServiceDaemon>>,
+    interfaces: InterfaceFilter,
+    session: Mutex<Option<BrowseSession>>,
+    registry: Arc<Mutex<ServiceRegistry>>,
 }
 
 const ADB_SERVICES: [&str; 2] = [
     "_adb-tls-pairing._tcp.local.",
     "_adb-tls-connect._tcp.local.",
 ];
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-fn event_to_adbservice(e: ServiceEvent) -> Option<AdbService> {
-    if let ServiceEvent::ServiceResolved(service) = e {
-        if let Some(ip) = service.get_addresses_v4().into_iter().next() {
+fn on_event(registry: &Mutex<ServiceRegistry>, service_type: AdbServiceType, e: ServiceEvent) {
+    match e {
+        ServiceEvent::ServiceResolved(service) => {
+            let Some(ip) = service.get_addresses_v4().into_iter().next() else {
+                log::warn!("Failed to get the ip from service: {service:?}");
+                return;
+            };
+            let mut addresses: Vec<String> = service
+                .get_addresses()
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect();
+            addresses.sort();
             let adbservice = AdbService {
                 name: service.get_fullname().into(),
                 ip: ip.to_string(),
                 port: service.get_port(),
                 domain: "local".into(),
+                service_type,
+                hostname: service.get_hostname().into(),
+                addresses,
+                txt: service
+                    .get_properties()
+                    .iter()
+                    .map(|p| (p.key().to_string(), p.val_str().to_string()))
+                    .collect(),
             };
+            registry.lock().unwrap().resolved(service_type, adbservice);
+        }
+        ServiceEvent::ServiceRemoved(_, fullname) => {
+            registry.lock().unwrap().removed(service_type, &fullname);
+        }
+        _ => {}
+    }
+}
 
-            return Some(adbservice);
+/// Retries `f` while the daemon's command channel is full, for up to `STOP_TIMEOUT`.
+fn retry_again<T>(mut f: impl FnMut() -> mdns_sd::Result<T>) -> Result<T, String> {
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    loop {
+        match f() {
+            Err(mdns_sd::Error::Again) if Instant::now() < deadline => {
+                std::thread::sleep(RETRY_INTERVAL)
+            }
+            result => return result.map_err(|e| e.to_string()),
         }
-        log::warn!("Failed to get the ip from service: {service:?}");
     }
+}
 
-    None
+fn browse(
+    recv: Receiver<ServiceEvent>,
+    registry: Arc<Mutex<ServiceRegistry>>,
+    service_type: AdbServiceType,
+    running: Arc<AtomicBool>,
+) {
+    loop {
+        match recv.recv_timeout(POLL_INTERVAL) {
+            Ok(ServiceEvent::SearchStopped(_)) => break,
+            Ok(event) => on_event(&registry, service_type, event),
+            Err(_) if recv.is_disconnected() || !running.load(Ordering::Acquire) => break,
+            Err(_) => {}
+        }
+    }
 }
 
 impl AdbMDnsDiscoveryService for AdbMdns {
     fn start(&self) -> Result<(), String> {
-        let recvs = ADB_SERVICES.map(|service_type| self.demon.browse(service_type).unwrap());
-        let sets = [self.pair_set.clone(), self.connect_set.clone()];
-
-        for (recv, set) in recvs.into_iter().zip(sets) {
-            std::thread::spawn(move || {
-                for event in recv {
-                    if let Some(adb_service) = event_to_adbservice(event) {
-                        let mut set = set.lock().unwrap();
-                        (*set).insert(adb_service);
-                    }
-                }
-            });
+        self.start_session(true)
+    }
+
+    fn stop(&self) -> Result<(), String> {
+        match self.session.lock().unwrap().take() {
+            Some(session) => stop_session(session),
+            None => Ok(()),
         }
+    }
 
-        Ok(())
+    fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Pairing)
     }
 
-    fn stop(&self) -> Result<(), String> {
-        for service in ADB_SERVICES {
-            let mut stop_result = self.demon.stop_browse(service);
-            while let Err(mdns_sd::Error::Again) = stop_result {
-                stop_result = self.demon.stop_browse(service);
+    fn adb_tls_connect(&self) -> HashSet<AdbService> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Connect)
+    }
+
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        self.registry.lock().unwrap().take_events()
+    }
+}
+
+impl AdbMdns {
+    /// Browses the adb services, with a worker thread per service type
+    /// feeding the registry when `workers`, leaving that to `next_event`
+    /// otherwise.
+    fn start_session(&self, workers: bool) -> Result<(), String> {
+        let mut session = self.session.lock().unwrap();
+        if session.is_some() {
+            return Err("mdns-sd discovery already started".into());
+        }
+
+        // The daemon is shut down by `stop`, create a new one when restarting
+        let demon = match self.demon.lock().unwrap().take() {
+            Some(demon) => demon,
+            None => new_daemon(&self.interfaces)?,
+        };
+        *self.registry.lock().unwrap() = ServiceRegistry::default();
+
+        let mut new_session = BrowseSession {
+            demon,
+            running: Arc::new(AtomicBool::new(true)),
+            workers: Vec::new(),
+            receivers: Vec::new(),
+        };
+        for (service, service_type) in ADB_SERVICES.into_iter().zip(AdbServiceType::ALL) {
+            let recv = match retry_again(|| new_session.demon.browse(service)) {
+                Ok(recv) => recv,
+                Err(e) => {
+                    let _ = stop_session(new_session);
+                    return Err(format!("Unable to browse {service}: {e}"));
+                }
+            };
+            if workers {
+                let registry = self.registry.clone();
+                let running = new_session.running.clone();
+                new_session.workers.push(std::thread::spawn(move || {
+                    browse(recv, registry, service_type, running)
+                }));
+            } else {
+                new_session.receivers.push((service_type, recv));
             }
+        }
 
-            if let Err(e) = stop_result {
-                return Err(e.to_string());
+        *session = Some(new_session);
+        Ok(())
+    }
+}
+
+/// The first event of any of `receivers`, `None` once they're disconnected.
+async fn recv_any(
+    receivers: &[(AdbServiceType, Receiver<ServiceEvent>)],
+) -> Option<(AdbServiceType, ServiceEvent)> {
+    let mut pending: Vec<_> = receivers
+        .iter()
+        .map(|(service_type, recv)| (*service_type, recv.recv_async()))
+        .collect();
+    poll_fn(|cx| {
+        for (service_type, recv) in &mut pending {
+            if let Poll::Ready(event) = Pin::new(recv).poll(cx) {
+                return Poll::Ready(event.ok().map(|event| (*service_type, event)));
             }
         }
+        Poll::Pending
+    })
+    .await
+}
 
-        while let Err(mdns_sd::Error::Again) = self.demon.shutdown() {}
+impl adb_mdns_discovery_service::AsyncAdbMDnsDiscoveryService for AdbMdns {
+    async fn start(&self) -> Result<(), String> {
+        self.start_session(false)
+    }
 
-        Ok(())
+    async fn stop(&self) -> Result<(), String> {
+        AdbMDnsDiscoveryService::stop(self)
     }
 
     fn adb_tls_pairing(&self) -> HashSet<AdbService> {
-        (*self.pair_set.lock().unwrap()).clone()
+        AdbMDnsDiscoveryService::adb_tls_pairing(self)
     }
 
     fn adb_tls_connect(&self) -> HashSet<AdbService> {
-        (*self.connect_set.lock().unwrap()).clone()
+        AdbMDnsDiscoveryService::adb_tls_connect(self)
+    }
+
+    async fn next_event(&self) -> Option<AdbServiceEvent> {
+        loop {
+            if let Some(event) = self.registry.lock().unwrap().next_event() {
+                return Some(event);
+            }
+            let receivers = match &*self.session.lock().unwrap() {
+                Some(session) if !session.receivers.is_empty() => session.receivers.clone(),
+                _ => return None,
+            };
+            match recv_any(&receivers).await? {
+                (_, ServiceEvent::SearchStopped(_)) => return None,
+                (service_type, event) => on_event(&self.registry, service_type, event),
+            }
+        }
     }
 }
 
+/// Stops browsing, joins the workers and shuts the daemon down, reporting every
+/// step that failed.
+fn stop_session(session: BrowseSession) -> Result<(), String> {
+    let mut errors = Vec::new();
+
+    for service in ADB_SERVICES {
+        if let Err(e) = retry_again(|| session.demon.stop_browse(service)) {
+            errors.push(format!("Unable to stop browsing {service}: {e}"));
+        }
+    }
+
+    session.running.store(false, Ordering::Release);
+    for worker in session.workers {
+        if worker.join().is_err() {
+            errors.push("mdns-sd worker panicked".into());
+        }
+    }
+
+    match retry_again(|| session.demon.shutdown()) {
+        Ok(status) => match status.recv_timeout(STOP_TIMEOUT) {
+            Ok(DaemonStatus::Shutdown) => {}
+            Ok(status) => errors.push(format!("mdns-sd daemon still {status:?}")),
+            Err(e) => errors.push(format!("mdns-sd daemon didn't shut down: {e}")),
+        },
+        Err(e) => errors.push(format!("Unable to shut down mdns-sd daemon: {e}")),
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn new_daemon(interfaces: &InterfaceFilter) -> Result<ServiceDaemon, String> {
+    let demon = ServiceDaemon::new().map_err(|e| e.to_string())?;
+    interfaces.apply(&demon)?;
+    Ok(demon)
+}
+
 impl AdbMdns {
     pub fn new() -> Result<AdbMdns, String> {
-        let demon = ServiceDaemon::new().map_err(|e| e.to_string())?;
+        Self::with_interfaces(&InterfaceFilter::default())
+    }
+
+    /// Only listens on the network interfaces allowed by `interfaces`.
+    pub fn with_interfaces(interfaces: &InterfaceFilter) -> Result<AdbMdns, String> {
         Ok(AdbMdns {
-            demon,
-            pair_set: Default::default(),
-            connect_set: Default::default(),
+            demon: Mutex::new(Some(new_daemon(interfaces)?)),
+            interfaces: interfaces.clone(),
+            session: Default::default(),
+            registry: Default::default(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mdns_sd::ServiceInfo;
+
+    use super::*;
+
+    #[test]
+    fn test_resolved_service_metadata() {
+        let registry = Mutex::new(ServiceRegistry::default());
+        let info = ServiceInfo::new(
+            ADB_SERVICES[1],
+            "adb-wg858lj7t959helz-si5LWZ",
+            "Android.local.",
+            "192.168.0.197,fe80::1",
+            34003,
+            &[("v", "1")][..],
+        )
+        .unwrap();
+        let fullname = info.get_fullname().to_string();
+
+        on_event(
+            &registry,
+            AdbServiceType::Connect,
+            ServiceEvent::ServiceResolved(info),
+        );
+        let services = registry.lock().unwrap().services(AdbServiceType::Connect);
+        let service = services.iter().next().unwrap();
+
+        assert_eq!(service.ip, "192.168.0.197");
+        assert_eq!(service.hostname, "Android.local.");
+        assert_eq!(service.addresses, ["192.168.0.197", "fe80::1"]);
+        assert_eq!(service.txt("v"), Some("1"));
+        assert_eq!(service.service_type, AdbServiceType::Connect);
+
+        on_event(
+            &registry,
+            AdbServiceType::Connect,
+            ServiceEvent::ServiceRemoved(ADB_SERVICES[1].into(), fullname),
+        );
+        assert!(registry
+            .lock()
+            .unwrap()
+            .services(AdbServiceType::Connect)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_restart() {
+        let mdns = AdbMdns::new().unwrap();
+
+        for _ in 0..2 {
+            mdns.start().unwrap();
+            assert!(mdns.start().is_err());
+            mdns.stop().unwrap();
+        }
+        assert!(mdns.stop().is_ok());
+    }
+
+    #[test]
+    fn test_withdrawn_service_is_removed() {
+        use crate::test_support::{loopback_interfaces, FakeMdnsResponder};
+
+        let responder = FakeMdnsResponder::loopback().unwrap();
+        let fullname = responder
+            .advertise(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            )
+            .unwrap();
+        let mdns = AdbMdns::with_interfaces(&loopback_interfaces()).unwrap();
+        mdns.start().unwrap();
+
+        let wait_for = |condition: &dyn Fn() -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !condition() && Instant::now() < deadline {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            condition()
+        };
+        assert!(wait_for(&|| !mdns.adb_tls_connect().is_empty()));
+
+        responder.withdraw(&fullname).unwrap();
+        assert!(wait_for(&|| mdns.adb_tls_connect().is_empty()));
+        mdns.stop().unwrap();
+
+        let events = mdns.events();
+        assert!(matches!(events[0], AdbServiceEvent::Added(..)));
+        assert!(matches!(events.last(), Some(AdbServiceEvent::Removed(..))));
+    }
+
+    #[test]
+    fn test_async_next_event() {
+        use crate::{
+            adb_mdns_discovery_service::AsyncAdbMDnsDiscoveryService,
+            test_support::{loopback_interfaces, FakeMdnsResponder},
+        };
+        use futures::executor::block_on;
+
+        let responder = FakeMdnsResponder::loopback().unwrap();
+        let fullname = responder
+            .advertise(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            )
+            .unwrap();
+        let mdns = AdbMdns::with_interfaces(&loopback_interfaces()).unwrap();
+        block_on(AsyncAdbMDnsDiscoveryService::start(&mdns)).unwrap();
+
+        let added = block_on(mdns.next_event());
+        assert!(matches!(
+            added,
+            Some(AdbServiceEvent::Added(AdbServiceType::Connect, _))
+        ));
+        assert_eq!(
+            AsyncAdbMDnsDiscoveryService::adb_tls_connect(&mdns).len(),
+            1
+        );
+
+        responder.withdraw(&fullname).unwrap();
+        let removed = block_on(mdns.next_event());
+        assert!(matches!(removed, Some(AdbServiceEvent::Removed(..))));
+
+        block_on(AsyncAdbMDnsDiscoveryService::stop(&mdns)).unwrap();
+        assert_eq!(block_on(mdns.next_event()), None);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    adb_device_authentication::AdbService,
+    adb_mdns_discovery_service::{
+        AdbMDnsDiscoveryService, AdbServiceEvent, AsyncAdbMDnsDiscoveryService,
+    },
+    client::{AdbClient, AsyncAdbClient},
+    pair_code::PairCode,
+    timer::Timer,
+};
+
+/// How often [`AdbMDnsDiscoveryService::events`] is polled while waiting for
+/// the next event.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Uses a blocking discovery backend or adb client through the async traits.
+///
+/// The calls run on the task's thread: discovery is polled, which is quick,
+/// but adb calls block until the adb server answers, prefer an async client
+/// like [`crate::TokioAdbClient`] for them. `timer` paces the polling.
+pub struct Blocking<'a, T: ?Sized, S> {
+    inner: &'a T,
+    timer: S,
+    running: AtomicBool,
+    pending: Mutex<VecDeque<AdbServiceEvent>>,
+}
+
+#[cfg(feature = "tokio")]
+impl<'a, T: ?Sized> Blocking<'a, T, crate::TokioTimer> {
+    pub fn new(inner: &'a T) -> Self {
+        Self::with_timer(inner, crate::TokioTimer)
+    }
+}
+
+impl<'a, T: ?Sized, S> Blocking<'a, T, S> {
+    pub fn with_timer(inner: &'a T, timer: S) -> Self {
+        Blocking {
+            inner,
+            timer,
+            running: AtomicBool::new(false),
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn pop_event(&self) -> Option<AdbServiceEvent> {
+        self.pending
+            .lock()
+            .expect("pending events lock poisoned")
+            .pop_front()
+    }
+}
+
+impl<T, S> AsyncAdbMDnsDiscoveryService for Blocking<'_, T, S>
+where
+    T: AdbMDnsDiscoveryService + Sync + ?Sized,
+    S: Timer,
+{
+    async fn start(&self) -> Result<(), String> {
+        self.inner.start()?;
+        self.running.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn stop(&self) -> Result<(), String> {
+        self.running.store(false, Ordering::SeqCst);
+        self.inner.stop()
+    }
+
+    fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+        self.inner.adb_tls_pairing()
+    }
+
+    fn adb_tls_connect(&self) -> HashSet<AdbService> {
+        self.inner.adb_tls_connect()
+    }
+
+    async fn next_event(&self) -> Option<AdbServiceEvent> {
+        loop {
+            if let Some(event) = self.pop_event() {
+                return Some(event);
+            }
+            if !self.running.load(Ordering::SeqCst) {
+                return None;
+            }
+            let events = self.inner.events();
+            if events.is_empty() {
+                self.timer.sleep(POLL_INTERVAL).await;
+            } else {
+                self.pending
+                    .lock()
+                    .expect("pending events lock poisoned")
+                    .extend(events);
+            }
+        }
+    }
+}
+
+impl<T: AdbClient + Sync + ?Sized, S: Sync> AsyncAdbClient for Blocking<'_, T, S> {
+    async fn adb_pair(&self, address: &str, code: &PairCode) -> Result<(), String> {
+        self.inner.adb_pair(address, code)
+    }
+
+    async fn adb_connect(&self, address: &str) -> Result<(), String> {
+        self.inner.adb_connect(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{adb_mdns_discovery_service::AdbServiceType, timer::tests::ThreadTimer};
+
+    #[derive(Default)]
+    struct ScriptedDiscovery {
+        events: Mutex<Vec<Vec<AdbServiceEvent>>>,
+    }
+
+    impl AdbMDnsDiscoveryService for ScriptedDiscovery {
+        fn start(&self) -> Result<(), String> {
+            Ok(())
+        }
+        fn stop(&self) -> Result<(), String> {
+            Ok(())
+        }
+        fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+            HashSet::new()
+        }
+        fn adb_tls_connect(&self) -> HashSet<AdbService> {
+            HashSet::new()
+        }
+        fn events(&self) -> Vec<AdbServiceEvent> {
+            self.events.lock().unwrap().pop().unwrap_or_default()
+        }
+    }
+
+    fn removed(port: u16) -> AdbServiceEvent {
+        AdbServiceEvent::Removed(
+            AdbServiceType::Connect,
+            AdbService::new(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-IvvXcC",
+                "192.168.0.197",
+                port,
+            ),
+        )
+    }
+
+    #[test]
+    fn test_next_event_until_stopped() {
+        let discovery = ScriptedDiscovery::default();
+        *discovery.events.lock().unwrap() = vec![vec![removed(2)], vec![], vec![removed(1)]];
+        let blocking = Blocking::with_timer(&discovery, ThreadTimer);
+
+        futures::executor::block_on(async {
+            blocking.start().await.unwrap();
+            assert_eq!(blocking.next_event().await, Some(removed(1)));
+            assert_eq!(blocking.next_event().await, Some(removed(2)));
+            blocking.stop().await.unwrap();
+            assert_eq!(blocking.next_event().await, None);
+        });
+    }
+}
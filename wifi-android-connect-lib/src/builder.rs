@@ -0,0 +1,208 @@
+use std::{net::SocketAddrV4, time::Duration};
+
+use crate::{
+    adb_device_authentication::{AdbStateTransition, StateObserver},
+    client::adb_server_address,
+    discovery_backend::DiscoveryBackend,
+    interface_filter::{InterfaceFilter, InterfaceMatcher},
+    pair_code::PairCode,
+    session_pair_name,
+    wifi_qrcode::validate_service_name,
+    WifiAndroidConnect, DEFAULT_PAIR_NAME,
+};
+
+/// Configures and validates a [`WifiAndroidConnect`].
+///
+/// ```
+/// use std::time::Duration;
+/// use wifi_android_connect_lib::WifiAndroidConnect;
+///
+/// let conn = WifiAndroidConnect::builder()
+///     .pair_name_prefix("My Laptop")
+///     .timeout(Duration::from_secs(120))
+///     .build()
+///     .unwrap();
+/// assert!(conn.pair_name().starts_with("My Laptop-"));
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct WifiAndroidConnectBuilder {
+    /// The pair name and its prefix.
+    pair_name: Option<(String, String)>,
+    pair_code: Option<PairCode>,
+    timeout: Option<Duration>,
+    backend: DiscoveryBackend,
+    adb_server_fallback: Option<bool>,
+    adb_server: Option<SocketAddrV4>,
+    interfaces: InterfaceFilter,
+    observers: Vec<StateObserver>,
+    #[cfg(feature = "record")]
+    record: Option<std::path::PathBuf>,
+}
+
+impl WifiAndroidConnectBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `name` as the pairing service name, exactly as given.
+    pub fn pair_name(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        self.pair_name = Some((name.clone(), name));
+        self
+    }
+
+    /// Uses `prefix` followed by a random token as the pairing service name.
+    pub fn pair_name_prefix(mut self, prefix: &str) -> Self {
+        self.pair_name = Some((session_pair_name(prefix), prefix.into()));
+        self
+    }
+
+    pub fn pair_code(mut self, code: PairCode) -> Self {
+        self.pair_code = Some(code);
+        self
+    }
+
+    /// Maximum time `connect` waits for a device, it waits forever by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// mDNS implementation used to discover devices, `mdns-sd` by default.
+    pub fn backend(mut self, backend: DiscoveryBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Whether to discover devices through the adb server when the backend
+    /// fails to start, enabled by default.
+    pub fn adb_server_fallback(mut self, enabled: bool) -> Self {
+        self.adb_server_fallback = Some(enabled);
+        self
+    }
+
+    /// Address of the adb server to pair and connect devices through,
+    /// `ADB_SERVER_SOCKET` or `127.0.0.1:5037` by default.
+    pub fn adb_server(mut self, address: SocketAddrV4) -> Self {
+        self.adb_server = Some(address);
+        self
+    }
+
+    /// Only discover devices on the interfaces matching `interface`, can be
+    /// called many times to include more interfaces.
+    pub fn include_interface(mut self, interface: InterfaceMatcher) -> Self {
+        self.interfaces.include.push(interface);
+        self
+    }
+
+    /// Never discover devices on the interfaces matching `interface`.
+    pub fn exclude_interface(mut self, interface: InterfaceMatcher) -> Self {
+        self.interfaces.exclude.push(interface);
+        self
+    }
+
+    /// Calls `observer` with every state change of the device authentication,
+    /// can be called many times to add more observers.
+    pub fn on_state_change(
+        mut self,
+        observer: impl Fn(&AdbStateTransition) + Send + Sync + 'static,
+    ) -> Self {
+        self.observers.push(StateObserver::new(observer));
+        self
+    }
+
+    /// Records the session to `path`, to replay it with
+    /// [`crate::SessionReplay`].
+    #[cfg(feature = "record")]
+    pub fn record(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.record = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> Result<WifiAndroidConnect, String> {
+        let (pair_name, pair_name_prefix) = self.pair_name.unwrap_or_else(|| {
+            (
+                session_pair_name(DEFAULT_PAIR_NAME),
+                DEFAULT_PAIR_NAME.into(),
+            )
+        });
+        validate_service_name(&pair_name)?;
+
+        if self.timeout == Some(Duration::ZERO) {
+            return Err("Timeout should be greater than zero".into());
+        }
+        let adb_server = self.adb_server.unwrap_or_else(adb_server_address);
+        if adb_server.port() == 0
+            || adb_server.ip().is_unspecified()
+            || adb_server.ip().is_multicast()
+            || adb_server.ip().is_broadcast()
+        {
+            return Err(format!("Invalid adb server address {adb_server}"));
+        }
+
+        Ok(WifiAndroidConnect {
+            pair_name,
+            pair_name_prefix,
+            pair_code: self.pair_code.unwrap_or_else(PairCode::random),
+            timeout: self.timeout,
+            backend: self.backend,
+            adb_server_fallback: self.adb_server_fallback.unwrap_or(true),
+            interfaces: self.interfaces,
+            adb_server,
+            observers: self.observers,
+            #[cfg(feature = "record")]
+            record: self.record,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_default() {
+        let conn = WifiAndroidConnectBuilder::new().build().unwrap();
+        assert!(conn.pair_name().starts_with(DEFAULT_PAIR_NAME));
+        assert_eq!(conn.timeout, None);
+    }
+
+    #[test]
+    fn test_build_exact_name_and_code() {
+        let code: PairCode = "012345".parse().unwrap();
+        let conn = WifiAndroidConnectBuilder::new()
+            .pair_name("studio")
+            .pair_code(code)
+            .build()
+            .unwrap();
+
+        assert_eq!(conn.pair_name(), "studio");
+        assert_eq!(conn.pair_code(), &code);
+    }
+
+    #[test]
+    fn test_build_adb_server() {
+        let server: SocketAddrV4 = "127.0.0.1:5038".parse().unwrap();
+        let conn = WifiAndroidConnectBuilder::new()
+            .adb_server(server)
+            .build()
+            .unwrap();
+
+        assert_eq!(conn.adb_server(), server);
+    }
+
+    #[test]
+    fn test_build_invalid() {
+        let builders = [
+            WifiAndroidConnectBuilder::new().pair_name(""),
+            WifiAndroidConnectBuilder::new().pair_name("a".repeat(64)),
+            WifiAndroidConnectBuilder::new().pair_name_prefix(&"a".repeat(60)),
+            WifiAndroidConnectBuilder::new().timeout(Duration::ZERO),
+            WifiAndroidConnectBuilder::new().adb_server("127.0.0.1:0".parse().unwrap()),
+            WifiAndroidConnectBuilder::new().adb_server("0.0.0.0:5037".parse().unwrap()),
+        ];
+        for builder in builders {
+            assert!(builder.clone().build().is_err(), "{builder:?}");
+        }
+    }
+}
@@ -1,66 +1,539 @@
-use std::net::SocketAddrV4;
+use std::{
+    future::Future,
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddrV4, TcpStream},
+};
 
+use crate::{
+    adb_device::{parse_devices, AdbDevice},
+    adb_instance_name::AdbInstanceName,
+    known_hosts,
+    pair_code::PairCode,
+};
+
+pub(crate) const ADB_SERVER_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5037);
+
+/// Overrides the adb server address like it does for adb, e.g. `tcp:5038` or
+/// `tcp:127.0.0.1:5038`.
+const ADB_SERVER_SOCKET: &str = "ADB_SERVER_SOCKET";
+
+fn parse_server_socket(socket: &str) -> Result<SocketAddrV4, String> {
+    let address = socket
+        .strip_prefix("tcp:")
+        .ok_or_else(|| format!("{ADB_SERVER_SOCKET} should start with tcp:, got {socket}"))?;
+    let (host, port) = address.rsplit_once(':').unwrap_or(("localhost", address));
+    let ip = match host {
+        "localhost" => Ipv4Addr::LOCALHOST,
+        host => host
+            .parse()
+            .map_err(|e| format!("Invalid {ADB_SERVER_SOCKET} host {host}: {e}"))?,
+    };
+    let port = port
+        .parse()
+        .map_err(|e| format!("Invalid {ADB_SERVER_SOCKET} port {port}: {e}"))?;
+    Ok(SocketAddrV4::new(ip, port))
+}
+
+/// The adb server address, from `ADB_SERVER_SOCKET` when it's set.
+pub(crate) fn adb_server_address() -> SocketAddrV4 {
+    let Ok(socket) = std::env::var(ADB_SERVER_SOCKET) else {
+        return ADB_SERVER_ADDRESS;
+    };
+    parse_server_socket(&socket).unwrap_or_else(|e| {
+        log::warn!("{e}, using {ADB_SERVER_ADDRESS}");
+        ADB_SERVER_ADDRESS
+    })
+}
+
+/// Pairs and connects devices through the adb server.
 pub trait AdbClient {
-    fn adb_pair(&self, address: &str, code: u32) -> Result<(), ()>;
-    fn adb_connect(&self, address: &str) -> Result<(), ()>;
+    fn adb_pair(&self, address: &str, code: &PairCode) -> Result<(), String>;
+    fn adb_connect(&self, address: &str) -> Result<(), String>;
+
+    /// Closes the connection to the device at `address`.
+    fn adb_disconnect(&self, address: &str) -> Result<(), String> {
+        Err(format!("Unable to disconnect {address}: unsupported"))
+    }
+
+    /// The devices known by the adb server, connected or not.
+    fn list_devices(&self) -> Result<Vec<AdbDevice>, String> {
+        Err("Unable to list devices: unsupported".into())
+    }
+
+    /// Disconnects the device with `serial` and removes it from adb's known
+    /// hosts, so adb stops connecting to it when it's discovered over mDNS.
+    ///
+    /// This doesn't revoke the pairing: the device still trusts this host and
+    /// `adb connect` keeps working, until the host is removed from the
+    /// device's wireless debugging settings. adb rewrites the file on its own
+    /// when pairing, restart the adb server (`adb kill-server`) to be sure it
+    /// sees the change.
+    fn forget(&self, serial: &str) -> Result<(), String> {
+        Err(format!("Unable to forget {serial}: unsupported"))
+    }
 }
 
-pub struct RustAdbClient;
+/// Same as [`AdbClient`], without blocking the async runtime.
+pub trait AsyncAdbClient {
+    fn adb_pair(
+        &self,
+        address: &str,
+        code: &PairCode,
+    ) -> impl Future<Output = Result<(), String>> + Send;
+    fn adb_connect(&self, address: &str) -> impl Future<Output = Result<(), String>> + Send;
+}
 
-impl AdbClient for RustAdbClient {
-    fn adb_pair(&self, address: &str, code: u32) -> Result<(), ()> {
-        let ipv4 = match address.parse::<SocketAddrV4>() {
-            Ok(add) => add,
-            Err(e) => {
-                log::error!("Unable to parse address: {address}");
-                log::error!("Error: {e:?}");
-                return Err(());
-            }
-        };
+/// [`AdbClient`] talking to an adb server over its smart socket protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RustAdbClient {
+    server: SocketAddrV4,
+}
 
-        let mut server = adb_client::ADBServer::default();
+impl RustAdbClient {
+    /// Client of the adb server listening on `127.0.0.1:5037`, or on
+    /// `ADB_SERVER_SOCKET` when it's set.
+    pub fn new() -> RustAdbClient {
+        RustAdbClient {
+            server: adb_server_address(),
+        }
+    }
 
-        match server.pair(ipv4, code) {
-            Ok(_ok) => {
-                log::info!("Device paired");
-                Ok(())
-            }
-            Err(e) => {
-                log::error!("Pair Error: {e:?}");
-                if let adb_client::RustADBError::ADBRequestFailed(s) = e {
-                    log::error!("msg: {s}");
-                }
-                Err(())
-            }
+    /// Client of the adb server listening on `server`, which isn't started
+    /// when it isn't running.
+    pub fn with_server(server: SocketAddrV4) -> RustAdbClient {
+        RustAdbClient { server }
+    }
+}
+
+impl Default for RustAdbClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn parse_device_address(address: &str) -> Result<SocketAddrV4, String> {
+    address.parse::<SocketAddrV4>().map_err(|e| {
+        log::error!("Unable to parse address: {address}");
+        log::error!("Error: {e:?}");
+        format!("Unable to parse address {address}: {e}")
+    })
+}
+
+/// The code is sent as typed, a leading zero is significant.
+pub(crate) fn pair_request(address: SocketAddrV4, code: &PairCode) -> String {
+    format!("host:pair:{code}:{address}")
+}
+
+pub(crate) fn pair_reply(reply: Result<String, String>) -> Result<(), String> {
+    match reply {
+        Ok(msg) if msg.starts_with("Successfully paired to") => {
+            log::info!("Device paired");
+            Ok(())
+        }
+        Ok(msg) | Err(msg) => {
+            log::error!("Pair Error: {msg}");
+            Err(msg)
+        }
+    }
+}
+
+pub(crate) fn connect_request(address: SocketAddrV4) -> String {
+    format!("host:connect:{address}")
+}
+
+pub(crate) fn connect_reply(address: &str, reply: Result<String, String>) -> Result<(), String> {
+    match reply {
+        Ok(msg) if msg.starts_with("connected to") => {
+            log::info!("Connected Device address: {address}");
+            Ok(())
+        }
+        Ok(msg) if msg.starts_with("already connected") => {
+            log::info!("Device already connected: {address}");
+            Ok(())
+        }
+        Ok(msg) | Err(msg) => {
+            log::error!("Unable to Connect Device address: {address}");
+            log::error!("Error: {msg}");
+            Err(msg)
         }
     }
+}
 
-    fn adb_connect(&self, address: &str) -> Result<(), ()> {
-        let ipv4 = match address.parse::<SocketAddrV4>() {
-            Ok(add) => add,
-            Err(e) => {
-                log::error!("Unable to parse address: {address}");
-                log::error!("Error: {e:?}");
-                return Err(());
-            }
-        };
+pub(crate) fn disconnect_request(address: &str) -> String {
+    format!("host:disconnect:{address}")
+}
 
-        let mut server = adb_client::ADBServer::default();
-        match server.connect_device(ipv4) {
-            Ok(_ok) => {
-                log::info!("Connected Device address: {address}");
-                Ok(())
-            }
-            Err(e) => {
-                log::error!("Error: {e:?}");
-                if let adb_client::RustADBError::ADBRequestFailed(msg) = e {
-                    if msg.contains("already connected") {
-                        return Ok(());
-                    }
-                }
-                log::error!("Unable to Connect Device address: {address}");
-                Err(())
+pub(crate) fn disconnect_reply(address: &str, reply: Result<String, String>) -> Result<(), String> {
+    match reply {
+        Ok(msg) if msg.starts_with("disconnected") => {
+            log::info!("Disconnected Device address: {address}");
+            Ok(())
+        }
+        Ok(msg) | Err(msg) => {
+            log::error!("Unable to disconnect Device address: {address}");
+            log::error!("Error: {msg}");
+            Err(msg)
+        }
+    }
+}
+
+/// The serial in `device`, either a serial or an instance name like
+/// `adb-wg858lj7t959helz-si5LWZ._adb-tls-connect._tcp` as listed by adb.
+fn device_serial(device: &str) -> String {
+    if !device.contains("._adb-tls-") {
+        return device.to_string();
+    }
+    match device.parse::<AdbInstanceName>() {
+        Ok(name) => name.serial,
+        Err(_) => device.to_string(),
+    }
+}
+
+impl RustAdbClient {
+    /// Same as [`AdbClient::forget`], with the pairings stored in
+    /// `known_hosts` instead of adb's.
+    pub(crate) fn forget_in(
+        &self,
+        known_hosts: &std::path::Path,
+        device: &str,
+    ) -> Result<(), String> {
+        let serial = device_serial(device);
+        // Devices connected by address can't be told apart, only the ones
+        // adb connected through mDNS carry their serial
+        for device in self.list_devices()? {
+            if device
+                .instance_name()
+                .is_some_and(|name| name.serial == serial)
+            {
+                self.adb_disconnect(&device.serial)?;
             }
         }
+        known_hosts::forget(known_hosts, &serial)
+    }
+}
+
+impl AdbClient for RustAdbClient {
+    fn adb_pair(&self, address: &str, code: &PairCode) -> Result<(), String> {
+        let ipv4 = parse_device_address(address)?;
+        pair_reply(adb_host_request(self.server, &pair_request(ipv4, code)))
+    }
+
+    fn adb_connect(&self, address: &str) -> Result<(), String> {
+        let ipv4 = parse_device_address(address)?;
+        connect_reply(
+            address,
+            adb_host_request(self.server, &connect_request(ipv4)),
+        )
+    }
+
+    fn adb_disconnect(&self, address: &str) -> Result<(), String> {
+        disconnect_reply(
+            address,
+            adb_host_request(self.server, &disconnect_request(address)),
+        )
+    }
+
+    fn list_devices(&self) -> Result<Vec<AdbDevice>, String> {
+        parse_devices(&adb_host_request(self.server, "host:devices")?)
+    }
+
+    fn forget(&self, serial: &str) -> Result<(), String> {
+        let known_hosts = known_hosts::known_hosts_path()
+            .ok_or("Unable to find adb's known hosts, set ANDROID_USER_HOME")?;
+        self.forget_in(&known_hosts, serial)
+    }
+}
+
+/// Whether to start the adb server after failing to reach `server`, only the
+/// default one is started.
+pub(crate) fn should_start_server(
+    server: SocketAddrV4,
+    error: std::io::Error,
+) -> Result<(), String> {
+    if server != ADB_SERVER_ADDRESS {
+        return Err(format!("Unable to reach adb server {server}: {error}"));
+    }
+    log::info!("adb server is not running, starting it");
+    Ok(())
+}
+
+pub(crate) fn server_started(
+    status: std::io::Result<std::process::ExitStatus>,
+) -> Result<(), String> {
+    let status = status.map_err(|e| format!("Unable to start adb server: {e}"))?;
+    if !status.success() {
+        return Err(format!("Unable to start adb server: {status}"));
+    }
+    Ok(())
+}
+
+/// `request` prefixed with its length, as the smart socket protocol frames it.
+pub(crate) fn encode_request(request: &str) -> String {
+    format!("{len:04x}{request}", len = request.len())
+}
+
+/// Whether the adb server accepted the request, from the status it replies
+/// with first. Its message follows either way.
+pub(crate) fn parse_status(status: &[u8; 4]) -> Result<bool, String> {
+    match status {
+        b"OKAY" => Ok(true),
+        b"FAIL" => Ok(false),
+        _ => Err(format!(
+            "Unexpected adb server status: {}",
+            String::from_utf8_lossy(status)
+        )),
+    }
+}
+
+pub(crate) fn parse_hex_length(length: &[u8; 4]) -> Result<usize, String> {
+    let length = std::str::from_utf8(length).map_err(|e| e.to_string())?;
+    usize::from_str_radix(length, 16).map_err(|e| e.to_string())
+}
+
+/// The reply to a request, its message being the error when it failed.
+pub(crate) fn host_reply(accepted: bool, message: Vec<u8>) -> Result<String, String> {
+    let message = String::from_utf8(message).map_err(|e| e.to_string())?;
+    if accepted {
+        Ok(message)
+    } else {
+        Err(message)
+    }
+}
+
+fn adb_server_stream(server: SocketAddrV4) -> Result<TcpStream, String> {
+    match TcpStream::connect(server) {
+        Ok(stream) => return Ok(stream),
+        Err(e) => should_start_server(server, e)?,
+    }
+    server_started(
+        std::process::Command::new("adb")
+            .arg("start-server")
+            .status(),
+    )?;
+    TcpStream::connect(ADB_SERVER_ADDRESS).map_err(|e| e.to_string())
+}
+
+fn read_message(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length).map_err(|e| e.to_string())?;
+    let mut msg = vec![0; parse_hex_length(&length)?];
+    stream.read_exact(&mut msg).map_err(|e| e.to_string())?;
+    Ok(msg)
+}
+
+/// Sends a host service request to the adb server using the smart socket
+/// protocol and returns the server's response message.
+pub(crate) fn adb_host_request(server: SocketAddrV4, request: &str) -> Result<String, String> {
+    let mut stream = adb_server_stream(server)?;
+    stream
+        .write_all(encode_request(request).as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut status = [0; 4];
+    stream.read_exact(&mut status).map_err(|e| e.to_string())?;
+    let accepted = parse_status(&status)?;
+    host_reply(accepted, read_message(&mut stream)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{AdbReply, FakeAdbServer, FakeDevice};
+
+    const DEVICE: &str = "192.168.0.197:34003";
+
+    fn client(server: &FakeAdbServer) -> RustAdbClient {
+        RustAdbClient::with_server(server.address())
+    }
+
+    #[test]
+    fn test_pair() {
+        let server = FakeAdbServer::start().unwrap();
+        let code = "012345".parse().unwrap();
+
+        assert_eq!(
+            client(&server).adb_pair("192.168.0.197:34317", &code),
+            Ok(())
+        );
+        assert_eq!(
+            server.requests(),
+            vec!["host:pair:012345:192.168.0.197:34317"]
+        );
+        assert_eq!(server.paired(), vec!["192.168.0.197:34317"]);
+    }
+
+    #[test]
+    fn test_pair_wrong_code() {
+        let server = FakeAdbServer::start().unwrap();
+        server.reply(
+            "host:pair",
+            AdbReply::Okay("failed: wrong password or connection was dropped".into()),
+        );
+
+        let result = client(&server).adb_pair("192.168.0.197:34317", &"123456".parse().unwrap());
+
+        assert_eq!(
+            result,
+            Err("failed: wrong password or connection was dropped".into())
+        );
+    }
+
+    #[test]
+    fn test_connect() {
+        let server = FakeAdbServer::start().unwrap();
+        let client = client(&server);
+
+        assert_eq!(client.adb_connect(DEVICE), Ok(()));
+        assert_eq!(client.adb_connect(DEVICE), Ok(()), "already connected");
+        assert_eq!(
+            server.requests(),
+            vec![
+                format!("host:connect:{DEVICE}"),
+                format!("host:connect:{DEVICE}")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_connect_failed() {
+        let server = FakeAdbServer::start().unwrap();
+        let refused = format!("failed to connect to '{DEVICE}': Connection refused");
+        server.reply("host:connect", AdbReply::Okay(refused.clone()));
+
+        assert_eq!(client(&server).adb_connect(DEVICE), Err(refused));
+    }
+
+    #[test]
+    fn test_pair_and_connect_device() {
+        let server = FakeAdbServer::start().unwrap();
+        server.add_device(FakeDevice {
+            pairing_address: "192.168.0.197:34317".into(),
+            pair_code: "024680".parse().unwrap(),
+            connect_address: DEVICE.into(),
+        });
+        let client = client(&server);
+
+        assert!(client.adb_connect(DEVICE).is_err(), "not paired yet");
+        assert!(client
+            .adb_pair("192.168.0.197:34317", &"123456".parse().unwrap())
+            .is_err());
+        assert_eq!(
+            client.adb_pair("192.168.0.197:34317", &"024680".parse().unwrap()),
+            Ok(())
+        );
+        assert_eq!(client.adb_connect(DEVICE), Ok(()));
+        assert!(client.adb_connect("192.168.0.197:41005").is_err());
+        assert_eq!(server.connected(), vec![DEVICE]);
+    }
+
+    #[test]
+    fn test_disconnect_and_list_devices() {
+        let server = FakeAdbServer::start().unwrap();
+        let client = client(&server);
+        client.adb_connect(DEVICE).unwrap();
+
+        assert_eq!(
+            client.list_devices(),
+            Ok(vec![AdbDevice {
+                serial: DEVICE.into(),
+                state: crate::TransportState::Device,
+            }])
+        );
+        assert_eq!(client.adb_disconnect(DEVICE), Ok(()));
+        assert_eq!(client.list_devices(), Ok(vec![]));
+        assert_eq!(
+            client.adb_disconnect(DEVICE),
+            Err(format!("no such device '{DEVICE}'"))
+        );
+    }
+
+    #[test]
+    fn test_forget() {
+        let server = FakeAdbServer::start().unwrap();
+        let connected = "adb-wg858lj7t959helz-si5LWZ._adb-tls-connect._tcp";
+        server.reply(
+            "host:devices",
+            AdbReply::Okay(format!("{connected}\tdevice\nR58M123ABC\tdevice\n")),
+        );
+        server.reply(
+            "host:disconnect",
+            AdbReply::Okay(format!("disconnected {connected}")),
+        );
+        let known_hosts = crate::known_hosts::tests::known_hosts_file(
+            "client-forget",
+            &["adb-wg858lj7t959helz", "adb-R58M123ABC"],
+        );
+
+        assert_eq!(client(&server).forget_in(&known_hosts, connected), Ok(()));
+        assert_eq!(
+            server.requests(),
+            [
+                "host:devices".to_string(),
+                format!("host:disconnect:{connected}")
+            ]
+        );
+        assert_eq!(
+            crate::known_hosts::paired_serials(&known_hosts),
+            Ok(vec!["R58M123ABC".to_string()])
+        );
+        assert!(client(&server)
+            .forget_in(&known_hosts, "wg858lj7t959helz")
+            .is_err());
+        std::fs::remove_dir_all(known_hosts.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_server_failure() {
+        let server = FakeAdbServer::start().unwrap();
+        server.reply("host:", AdbReply::Fail("unknown host service".into()));
+        let client = client(&server);
+
+        assert_eq!(
+            client.adb_connect(DEVICE),
+            Err("unknown host service".into())
+        );
+        assert_eq!(
+            client.adb_pair(DEVICE, &"123456".parse().unwrap()),
+            Err("unknown host service".into())
+        );
+    }
+
+    #[test]
+    fn test_server_unreachable() {
+        let address = FakeAdbServer::start().unwrap().address();
+
+        let result = RustAdbClient::with_server(address).adb_connect(DEVICE);
+
+        assert!(result.unwrap_err().contains("Unable to reach adb server"));
+    }
+
+    #[test]
+    fn test_parse_server_socket() {
+        let server = |port| SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+        assert_eq!(parse_server_socket("tcp:5038"), Ok(server(5038)));
+        assert_eq!(parse_server_socket("tcp:localhost:5038"), Ok(server(5038)));
+        assert_eq!(parse_server_socket("tcp:127.0.0.1:5037"), Ok(server(5037)));
+        for socket in ["5037", "tcp:", "tcp:android:5037", "tcp:127.0.0.1:adb"] {
+            assert!(parse_server_socket(socket).is_err(), "{socket}");
+        }
+    }
+
+    #[test]
+    fn test_framing() {
+        assert_eq!(encode_request("host:devices"), "000chost:devices");
+        assert_eq!(parse_status(b"OKAY"), Ok(true));
+        assert_eq!(parse_status(b"FAIL"), Ok(false));
+        assert!(parse_status(b"WHAT").is_err());
+        assert_eq!(parse_hex_length(b"001a"), Ok(26));
+        assert!(parse_hex_length(b"zzzz").is_err());
+        assert_eq!(host_reply(false, b"no".to_vec()), Err("no".into()));
+    }
+
+    #[test]
+    fn test_invalid_address() {
+        let server = FakeAdbServer::start().unwrap();
+
+        assert!(client(&server).adb_connect("android.local:34003").is_err());
+        assert!(server.requests().is_empty());
     }
 }
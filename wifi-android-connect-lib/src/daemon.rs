@@ -0,0 +1,873 @@
+//! Runs the discovery in the background, controlled over a Unix socket.
+//!
+//! The protocol is JSON-RPC 2.0, one request and one response per line:
+//!
+//! ```text
+//! {"jsonrpc":"2.0","id":1,"method":"start_pairing","params":{"pair_code":"123456"}}
+//! {"jsonrpc":"2.0","id":1,"result":{"session":1,"payload":"WIFI:T:ADB;...","qrcode":"..."}}
+//! ```
+//!
+//! Methods:
+//! - `start_pairing` [`PairingRequest`] -> [`PairingSession`], pairs and
+//!   connects the device scanning the QR code in the background
+//! - `session_status` `{"session": 1}` -> [`SessionStatus`], the session is
+//!   forgotten once it told the session ended
+//! - `qr_payload` `{"session": 1}` -> the session's QR code payload
+//! - `list_devices` -> `[{"serial": "192.168.0.197:34003", "state": "device"}]`
+//! - `reconnect` `{"serial": "wg858lj7t959helz"}` -> the address connected to
+//! - `shutdown`
+
+use std::{
+    collections::HashMap,
+    fs::{self, DirBuilder},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    adb_device_authentication::{AdbConnectionState, AdbService},
+    adb_mdns_discovery_service::{AdbMDnsDiscoveryService, AdbServiceEvent},
+    client::AdbClient,
+    WifiAndroidConnect,
+};
+
+/// How long a pairing session waits for its device when the daemon has no
+/// timeout.
+pub(crate) const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+/// How long an ended session's result is kept when no client reads it.
+const SESSION_RESULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The lib's own errors, e.g. the adb server being unreachable.
+const SERVER_ERROR: i64 = -32000;
+
+/// `$XDG_RUNTIME_DIR/wifi-android-connect.sock`, or
+/// `wifi-android-connect-<uid>/daemon.sock` in the temporary directory when
+/// it isn't set.
+pub fn default_socket_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("wifi-android-connect.sock"),
+        None => std::env::temp_dir()
+            .join(format!("wifi-android-connect-{}", euid()))
+            .join("daemon.sock"),
+    }
+}
+
+fn euid() -> u32 {
+    // SAFETY: geteuid has no preconditions and never fails
+    unsafe { libc::geteuid() }
+}
+
+/// Errors unless `path` is a socket of this user: another user's daemon
+/// would get the pairing requests and show its own QR codes.
+pub(crate) fn check_socket_owner(path: &Path) -> Result<(), String> {
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|e| format!("Unable to read {}: {e}", path.display()))?;
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} isn't a socket", path.display()));
+    }
+    if metadata.uid() != euid() {
+        return Err(format!("{} belongs to another user", path.display()));
+    }
+    Ok(())
+}
+
+/// Creates the directory of the socket at `path` for this user only, or
+/// checks no one else can replace the socket in the existing one.
+fn prepare_socket_dir(path: &Path) -> Result<(), String> {
+    let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(format!("Unable to create {}: {e}", dir.display())),
+    }
+
+    let metadata =
+        fs::metadata(dir).map_err(|e| format!("Unable to read {}: {e}", dir.display()))?;
+    if !metadata.is_dir() {
+        return Err(format!("{} isn't a directory", dir.display()));
+    }
+    if metadata.uid() != euid() && metadata.uid() != 0 {
+        return Err(format!("{} belongs to another user", dir.display()));
+    }
+    // Shared directories like /tmp are fine with the sticky bit, others
+    // can't remove our socket then
+    let mode = metadata.mode();
+    if mode & 0o022 != 0 && mode & 0o1000 == 0 {
+        return Err(format!("{} is writable by other users", dir.display()));
+    }
+    Ok(())
+}
+
+/// Parameters of `start_pairing`, the daemon picks what isn't set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PairingRequest {
+    /// Prefix of the pairing service name, a random token is appended to it.
+    pub name_prefix: Option<String>,
+    /// 6 digits pair code.
+    pub pair_code: Option<String>,
+    pub timeout_secs: Option<u64>,
+}
+
+/// Result of `start_pairing`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairingSession {
+    pub session: u64,
+    /// `WIFI:T:ADB;S:<name>;P:<code>;;` text of the QR code.
+    pub payload: String,
+    /// The QR code rendered for a terminal.
+    pub qrcode: String,
+}
+
+/// Result of `session_status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStatus {
+    /// Where the authentication is at, e.g. `Pairing with 192.168.0.197:34317`.
+    pub state: String,
+    /// Whether the session ended, connected or not.
+    pub done: bool,
+    /// Why the session ended without connecting.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DeviceEntry {
+    pub serial: String,
+    pub state: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SessionParams {
+    pub session: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ReconnectParams {
+    pub serial: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Request {
+    #[serde(default)]
+    pub jsonrpc: String,
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Response {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Response {
+            jsonrpc: "2.0".into(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+fn server_error(message: String) -> RpcError {
+    RpcError {
+        code: SERVER_ERROR,
+        message,
+    }
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    // Methods without parameters may get them omitted
+    let params = if params.is_null() {
+        Value::Object(Default::default())
+    } else {
+        params
+    };
+    serde_json::from_value(params).map_err(|e| RpcError {
+        code: INVALID_PARAMS,
+        message: format!("Invalid params: {e}"),
+    })
+}
+
+fn to_value(value: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| server_error(e.to_string()))
+}
+
+/// The daemon's discovery as seen by a pairing session: always running, the
+/// sessions share it.
+struct Warm<'a> {
+    discovery: &'a (dyn AdbMDnsDiscoveryService + Send + Sync),
+    sessions: &'a Mutex<HashMap<u64, Session>>,
+    session: u64,
+}
+
+impl AdbMDnsDiscoveryService for Warm<'_> {
+    fn start(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn adb_tls_pairing(&self) -> std::collections::HashSet<AdbService> {
+        self.discovery.adb_tls_pairing()
+    }
+
+    fn adb_tls_connect(&self) -> std::collections::HashSet<AdbService> {
+        self.discovery.adb_tls_connect()
+    }
+
+    /// The events the daemon handed to this session, see [`dispatch_events`].
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        self.sessions
+            .lock()
+            .expect("sessions lock poisoned")
+            .get_mut(&self.session)
+            .map(|session| std::mem::take(&mut session.events))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    payload: String,
+    state: AdbConnectionState,
+    result: Option<Result<(), String>>,
+    ended: Option<Instant>,
+    /// Discovery events the session didn't handle yet.
+    events: Vec<AdbServiceEvent>,
+}
+
+struct DaemonState<C> {
+    discovery: Arc<dyn AdbMDnsDiscoveryService + Send + Sync>,
+    client: Arc<C>,
+    timeout: Duration,
+    sessions: Mutex<HashMap<u64, Session>>,
+    next_session: AtomicU64,
+    running: AtomicBool,
+}
+
+/// Holds a started discovery and pairs/connects devices with `client` for the
+/// clients of its Unix socket.
+pub struct Daemon<C> {
+    listener: UnixListener,
+    path: PathBuf,
+    state: Arc<DaemonState<C>>,
+}
+
+impl<C: AdbClient + Send + Sync + 'static> Daemon<C> {
+    /// Starts `discovery` and listens on `path`. Pairing sessions give up
+    /// after `timeout`.
+    pub fn bind(
+        path: impl AsRef<Path>,
+        discovery: Arc<dyn AdbMDnsDiscoveryService + Send + Sync>,
+        client: C,
+        timeout: Duration,
+    ) -> Result<Daemon<C>, String> {
+        let path = path.as_ref().to_path_buf();
+        prepare_socket_dir(&path)?;
+        if UnixStream::connect(&path).is_ok() {
+            return Err(format!(
+                "A daemon is already listening on {}",
+                path.display()
+            ));
+        }
+        if fs::symlink_metadata(&path).is_ok() {
+            // Left behind by a daemon that didn't exit cleanly
+            check_socket_owner(&path)?;
+            fs::remove_file(&path)
+                .map_err(|e| format!("Unable to remove {}: {e}", path.display()))?;
+        }
+        let listener = UnixListener::bind(&path)
+            .map_err(|e| format!("Unable to listen on {}: {e}", path.display()))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+            .map_err(|e| format!("Unable to restrict {}: {e}", path.display()))?;
+        discovery.start()?;
+        log::info!("daemon listening on {}", path.display());
+
+        Ok(Daemon {
+            listener,
+            path,
+            state: Arc::new(DaemonState {
+                discovery,
+                client: Arc::new(client),
+                timeout,
+                sessions: Mutex::new(HashMap::new()),
+                next_session: AtomicU64::new(1),
+                running: AtomicBool::new(true),
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serves the clients until one asks for `shutdown`.
+    pub fn run(&self) -> Result<(), String> {
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while self.state.running.load(Ordering::SeqCst) {
+                    dispatch_events(&self.state);
+                    std::thread::sleep(crate::CONNECT_POLL_INTERVAL);
+                }
+            });
+            self.accept();
+        });
+        Ok(())
+    }
+
+    fn accept(&self) {
+        for stream in self.listener.incoming() {
+            if !self.state.running.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("daemon: {e}");
+                    continue;
+                }
+            };
+            let state = self.state.clone();
+            let path = self.path.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = serve(stream, &state) {
+                    log::warn!("daemon: {e}");
+                }
+                if !state.running.load(Ordering::SeqCst) {
+                    // Wakes the listener blocked in accept
+                    let _ = UnixStream::connect(path);
+                }
+            });
+        }
+    }
+}
+
+impl<C> Drop for Daemon<C> {
+    fn drop(&mut self) {
+        if let Err(e) = self.state.discovery.stop() {
+            log::warn!("daemon: {e}");
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Takes the events of the shared discovery, so they don't pile up while the
+/// daemon runs, and hands a copy to every session still running.
+fn dispatch_events<C>(state: &DaemonState<C>) {
+    let events = state.discovery.events();
+    if events.is_empty() {
+        return;
+    }
+    log::debug!("daemon discovery events {events:?}");
+    let mut sessions = state.sessions.lock().expect("sessions lock poisoned");
+    for session in sessions.values_mut() {
+        if session.result.is_none() {
+            session.events.extend(events.iter().cloned());
+        }
+    }
+}
+
+/// Answers the requests sent on `stream`, one per line, until it's closed.
+fn serve<C: AdbClient + Send + Sync + 'static>(
+    stream: UnixStream,
+    state: &Arc<DaemonState<C>>,
+) -> Result<(), String> {
+    let mut writer = stream.try_clone().map_err(|e| e.to_string())?;
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                log::debug!("daemon request {request:?}");
+                Response::new(request.id, handle(state, &request.method, request.params))
+            }
+            Err(e) => Response::new(
+                Value::Null,
+                Err(RpcError {
+                    code: PARSE_ERROR,
+                    message: format!("Parse error: {e}"),
+                }),
+            ),
+        };
+        let mut response = serde_json::to_string(&response).map_err(|e| e.to_string())?;
+        response.push('\n');
+        writer
+            .write_all(response.as_bytes())
+            .map_err(|e| e.to_string())?;
+        if !state.running.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn handle<C: AdbClient + Send + Sync + 'static>(
+    state: &Arc<DaemonState<C>>,
+    method: &str,
+    raw_params: Value,
+) -> Result<Value, RpcError> {
+    match method {
+        "start_pairing" => to_value(start_pairing(state, params(raw_params)?)?),
+        "session_status" => {
+            let SessionParams { session: id } = params(raw_params)?;
+            let mut sessions = state.sessions.lock().expect("sessions lock poisoned");
+            let session = sessions.get(&id).ok_or_else(|| unknown_session(id))?;
+            let status = SessionStatus {
+                state: session.state.to_string(),
+                done: session.result.is_some(),
+                error: session.result.clone().and_then(Result::err),
+            };
+            // The result was read, the session is of no use anymore
+            if status.done {
+                sessions.remove(&id);
+            }
+            to_value(status)
+        }
+        "qr_payload" => {
+            let SessionParams { session } = params(raw_params)?;
+            let sessions = state.sessions.lock().expect("sessions lock poisoned");
+            let session = sessions
+                .get(&session)
+                .ok_or_else(|| unknown_session(session))?;
+            to_value(&session.payload)
+        }
+        "list_devices" => {
+            let devices = state.client.list_devices().map_err(server_error)?;
+            to_value(
+                devices
+                    .into_iter()
+                    .map(|device| DeviceEntry {
+                        serial: device.serial,
+                        state: device.state.to_string(),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        }
+        "reconnect" => {
+            let ReconnectParams { serial } = params(raw_params)?;
+            to_value(reconnect(state, &serial).map_err(server_error)?)
+        }
+        "shutdown" => {
+            state.running.store(false, Ordering::SeqCst);
+            Ok(Value::Null)
+        }
+        method => Err(RpcError {
+            code: METHOD_NOT_FOUND,
+            message: format!("Method not found: {method}"),
+        }),
+    }
+}
+
+fn unknown_session(session: u64) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: format!("Unknown session {session}"),
+    }
+}
+
+fn start_pairing<C: AdbClient + Send + Sync + 'static>(
+    state: &Arc<DaemonState<C>>,
+    request: PairingRequest,
+) -> Result<PairingSession, RpcError> {
+    let id = state.next_session.fetch_add(1, Ordering::SeqCst);
+    let observed = state.clone();
+    let mut builder = WifiAndroidConnect::builder()
+        .timeout(
+            request
+                .timeout_secs
+                .map_or(state.timeout, Duration::from_secs),
+        )
+        .on_state_change(move |transition| {
+            let mut sessions = observed.sessions.lock().expect("sessions lock poisoned");
+            if let Some(session) = sessions.get_mut(&id) {
+                session.state = transition.to.clone();
+            }
+        });
+    if let Some(prefix) = &request.name_prefix {
+        builder = builder.pair_name_prefix(prefix);
+    }
+    if let Some(code) = &request.pair_code {
+        let code = code.parse().map_err(|message| RpcError {
+            code: INVALID_PARAMS,
+            message,
+        })?;
+        builder = builder.pair_code(code);
+    }
+    let conn = builder.build().map_err(server_error)?;
+    let payload = conn.qrcode_payload().map_err(server_error)?;
+    let qrcode = conn.qrcode_img().map_err(server_error)?;
+
+    let mut sessions = state.sessions.lock().expect("sessions lock poisoned");
+    sessions.retain(|_, session| {
+        session
+            .ended
+            .is_none_or(|ended| ended.elapsed() < SESSION_RESULT_TTL)
+    });
+    sessions.insert(
+        id,
+        Session {
+            payload: payload.clone(),
+            state: AdbConnectionState::Unpaired,
+            result: None,
+            ended: None,
+            events: Vec::new(),
+        },
+    );
+    drop(sessions);
+    let state = state.clone();
+    std::thread::spawn(move || {
+        let warm = Warm {
+            discovery: state.discovery.as_ref(),
+            sessions: &state.sessions,
+            session: id,
+        };
+        let result = conn.connect_with(&warm, state.client.as_ref());
+        log::info!("pairing session {id} ended: {result:?}");
+        if let Some(session) = state
+            .sessions
+            .lock()
+            .expect("sessions lock poisoned")
+            .get_mut(&id)
+        {
+            session.result = Some(result);
+            session.ended = Some(Instant::now());
+        }
+    });
+
+    Ok(PairingSession {
+        session: id,
+        payload,
+        qrcode,
+    })
+}
+
+/// Connects the device with `serial` to the address its connect service is
+/// advertised on.
+fn reconnect<C: AdbClient>(state: &DaemonState<C>, serial: &str) -> Result<String, String> {
+    let address = state
+        .discovery
+        .adb_tls_connect()
+        .into_iter()
+        .find(|service| {
+            service
+                .adb_instance_name()
+                .is_some_and(|name| name.serial == serial)
+        })
+        .map(|service| service.address())
+        .ok_or_else(|| format!("{serial} isn't advertising its connect service"))?;
+    state.client.adb_connect(&address)?;
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        adb_mdns_discovery_service::AdbServiceType,
+        test_support::{loopback_interfaces, FakeAdbServer, FakeDevice, FakeMdnsResponder},
+        AdbMdns, AdbQrPayload, DaemonClient, RustAdbClient,
+    };
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "wifi-android-connect-{name}-{}.sock",
+            std::process::id()
+        ))
+    }
+
+    struct NoDiscovery;
+
+    impl AdbMDnsDiscoveryService for NoDiscovery {
+        fn start(&self) -> Result<(), String> {
+            Ok(())
+        }
+        fn stop(&self) -> Result<(), String> {
+            Ok(())
+        }
+        fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+            HashSet::new()
+        }
+        fn adb_tls_connect(&self) -> HashSet<AdbService> {
+            HashSet::new()
+        }
+    }
+
+    #[test]
+    fn test_pair_and_reconnect_through_daemon() {
+        let server = FakeAdbServer::start().unwrap();
+        let adb = RustAdbClient::with_server(server.address());
+        let responder = FakeMdnsResponder::loopback().unwrap();
+        let discovery = Arc::new(AdbMdns::with_interfaces(&loopback_interfaces()).unwrap());
+        let daemon =
+            Daemon::bind(socket_path("pair"), discovery, adb, Duration::from_secs(10)).unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| daemon.run());
+            let client = DaemonClient::new(daemon.path());
+
+            let session = client
+                .start_pairing(&PairingRequest {
+                    pair_code: Some("024680".into()),
+                    ..Default::default()
+                })
+                .unwrap();
+            // The phone scans the QR code
+            let payload = AdbQrPayload::parse(&session.payload).unwrap();
+            server.add_device(FakeDevice {
+                pairing_address: "127.0.0.1:34317".into(),
+                pair_code: payload.pair_code,
+                connect_address: "127.0.0.1:34003".into(),
+            });
+            responder
+                .advertise(AdbServiceType::Pairing, &payload.name, 34317)
+                .unwrap();
+            responder
+                .advertise(
+                    AdbServiceType::Connect,
+                    "adb-wg858lj7t959helz-si5LWZ",
+                    34003,
+                )
+                .unwrap();
+
+            assert_eq!(
+                client.qr_payload(session.session),
+                Ok(session.payload.clone())
+            );
+            let mut states = Vec::new();
+            assert_eq!(
+                client.wait(session.session, |state| states.push(state.to_string())),
+                Ok(())
+            );
+            assert_eq!(
+                states.last().map(String::as_str),
+                Some("Connected to 127.0.0.1:34003")
+            );
+            // Forgotten once its result was read
+            assert!(client.session_status(session.session).is_err());
+            assert_eq!(client.list_devices().unwrap()[0].serial, "127.0.0.1:34003");
+
+            adb.adb_disconnect("127.0.0.1:34003").unwrap();
+            assert_eq!(
+                client.reconnect("wg858lj7t959helz"),
+                Ok("127.0.0.1:34003".into())
+            );
+            assert_eq!(server.connected(), ["127.0.0.1:34003"]);
+            assert!(client.reconnect("R58M123ABC").is_err());
+
+            client.shutdown().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_sessions_get_discovery_events() {
+        const NAME: &str = "adb-r58m123abc-si5LWZ";
+        let server = FakeAdbServer::start().unwrap();
+        server.add_device(FakeDevice {
+            pairing_address: "127.0.0.1:35317".into(),
+            pair_code: "024680".parse().unwrap(),
+            connect_address: "127.0.0.1:35005".into(),
+        });
+        let responder = FakeMdnsResponder::loopback().unwrap();
+        let discovery = Arc::new(AdbMdns::with_interfaces(&loopback_interfaces()).unwrap());
+        let daemon = Daemon::bind(
+            socket_path("events"),
+            discovery.clone(),
+            RustAdbClient::with_server(server.address()),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        let requested = |request: &str| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                if server.requests().iter().any(|r| r == request) {
+                    return true;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            false
+        };
+
+        let (first, moved) = std::thread::scope(|scope| {
+            scope.spawn(|| daemon.run());
+            let client = DaemonClient::new(daemon.path());
+            client
+                .start_pairing(&PairingRequest {
+                    pair_code: Some("024680".into()),
+                    ..Default::default()
+                })
+                .unwrap();
+
+            let fullname = responder
+                .advertise(AdbServiceType::Connect, NAME, 35003)
+                .unwrap();
+            let first = requested("host:connect:127.0.0.1:35003");
+            // Wireless debugging restarted on another port
+            responder.withdraw(&fullname).unwrap();
+            responder
+                .advertise(AdbServiceType::Connect, NAME, 35005)
+                .unwrap();
+
+            let moved = requested("host:connect:127.0.0.1:35005");
+
+            client.shutdown().unwrap();
+            (first, moved)
+        });
+
+        assert!(first);
+        // The session forgot the dead endpoint and tried the new one
+        assert!(moved, "{:?}", server.requests());
+        // Drained by the daemon, other tests' services may have come since
+        assert!(!discovery.events().iter().any(|event| match event {
+            AdbServiceEvent::Added(_, service)
+            | AdbServiceEvent::Updated(_, service)
+            | AdbServiceEvent::Removed(_, service) => service.instance_name() == NAME,
+        }));
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let server = FakeAdbServer::start().unwrap();
+        let daemon = Daemon::bind(
+            socket_path("errors"),
+            Arc::new(NoDiscovery),
+            RustAdbClient::with_server(server.address()),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| daemon.run());
+            let request = |line: &str| {
+                let mut stream = UnixStream::connect(daemon.path()).unwrap();
+                stream.write_all(format!("{line}\n").as_bytes()).unwrap();
+                let mut response = String::new();
+                BufReader::new(stream).read_line(&mut response).unwrap();
+                serde_json::from_str::<Response>(&response).unwrap()
+            };
+
+            let code = |response: Response| response.error.map(|error| error.code);
+            assert_eq!(code(request("not json")), Some(PARSE_ERROR));
+            assert_eq!(
+                code(request(r#"{"jsonrpc":"2.0","id":1,"method":"pair"}"#)),
+                Some(METHOD_NOT_FOUND)
+            );
+            assert_eq!(
+                code(request(
+                    r#"{"jsonrpc":"2.0","id":2,"method":"session_status","params":{"session":7}}"#
+                )),
+                Some(INVALID_PARAMS)
+            );
+            assert_eq!(
+                code(request(
+                    r#"{"jsonrpc":"2.0","id":3,"method":"start_pairing","params":{"pair_code":"12"}}"#
+                )),
+                Some(INVALID_PARAMS)
+            );
+            let listed = request(r#"{"jsonrpc":"2.0","id":4,"method":"list_devices"}"#);
+            assert_eq!(listed.id, Value::from(4));
+            assert_eq!(listed.result, Some(Value::Array(vec![])));
+
+            DaemonClient::new(daemon.path()).shutdown().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_one_daemon_per_socket() {
+        let path = socket_path("single");
+        let daemon = Daemon::bind(
+            &path,
+            Arc::new(NoDiscovery),
+            RustAdbClient::new(),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+
+        let second = Daemon::bind(
+            &path,
+            Arc::new(NoDiscovery),
+            RustAdbClient::new(),
+            Duration::from_secs(1),
+        );
+
+        assert!(second.is_err());
+        drop(daemon);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_socket_in_private_directory() {
+        let dir = std::env::temp_dir().join(format!(
+            "wifi-android-connect-private-{}",
+            std::process::id()
+        ));
+        let path = dir.join("daemon.sock");
+        let daemon = Daemon::bind(
+            &path,
+            Arc::new(NoDiscovery),
+            RustAdbClient::new(),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(check_socket_owner(&path), Ok(()));
+        drop(daemon);
+
+        // Not a socket a daemon could have left behind
+        fs::write(&path, "").unwrap();
+        assert!(!DaemonClient::new(&path).is_running());
+        let bound = Daemon::bind(
+            &path,
+            Arc::new(NoDiscovery),
+            RustAdbClient::new(),
+            Duration::from_secs(1),
+        );
+        assert!(bound.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    adb_device::AdbDevice,
+    daemon::{
+        check_socket_owner, DeviceEntry, PairingRequest, PairingSession, ReconnectParams, Request,
+        Response, SessionParams, SessionStatus,
+    },
+};
+
+/// How often [`DaemonClient::wait`] asks for the session's status.
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long a call waits for the daemon, which may be stuck, e.g. stopped.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Client of a [`crate::Daemon`], one connection per call.
+#[derive(Debug)]
+pub struct DaemonClient {
+    path: PathBuf,
+    next_id: AtomicU64,
+    timeout: Duration,
+}
+
+impl DaemonClient {
+    pub fn new(path: impl AsRef<Path>) -> DaemonClient {
+        DaemonClient {
+            path: path.as_ref().to_path_buf(),
+            next_id: AtomicU64::new(1),
+            timeout: CALL_TIMEOUT,
+        }
+    }
+
+    /// Whether a daemon of this user listens on the socket.
+    pub fn is_running(&self) -> bool {
+        if !self.path.exists() {
+            return false;
+        }
+        if let Err(e) = check_socket_owner(&self.path) {
+            log::warn!("Ignoring the daemon: {e}");
+            return false;
+        }
+        UnixStream::connect(&self.path).is_ok()
+    }
+
+    fn call<T: DeserializeOwned>(&self, method: &str, params: impl Serialize) -> Result<T, String> {
+        check_socket_owner(&self.path)?;
+        let mut stream = UnixStream::connect(&self.path)
+            .map_err(|e| format!("Unable to reach the daemon on {}: {e}", self.path.display()))?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .and_then(|()| stream.set_write_timeout(Some(self.timeout)))
+            .map_err(|e| e.to_string())?;
+        let request = Request {
+            jsonrpc: "2.0".into(),
+            id: self.next_id.fetch_add(1, Ordering::SeqCst).into(),
+            method: method.into(),
+            params: serde_json::to_value(params).map_err(|e| e.to_string())?,
+        };
+        let mut line = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+        line.push('\n');
+        stream
+            .write_all(line.as_bytes())
+            .map_err(|e| self.io_error(e))?;
+
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .map_err(|e| self.io_error(e))?;
+        let response: Response = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid daemon response {line:?}: {e}"))?;
+        if let Some(error) = response.error {
+            return Err(error.message);
+        }
+        serde_json::from_value(response.result.unwrap_or(Value::Null)).map_err(|e| e.to_string())
+    }
+
+    fn io_error(&self, e: io::Error) -> String {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => format!(
+                "The daemon on {} didn't answer within {}s",
+                self.path.display(),
+                self.timeout.as_secs_f32()
+            ),
+            _ => e.to_string(),
+        }
+    }
+
+    /// Opens a pairing session, the daemon pairs and connects the device
+    /// scanning the returned QR code.
+    pub fn start_pairing(&self, request: &PairingRequest) -> Result<PairingSession, String> {
+        self.call("start_pairing", request)
+    }
+
+    pub fn session_status(&self, session: u64) -> Result<SessionStatus, String> {
+        self.call("session_status", SessionParams { session })
+    }
+
+    pub fn qr_payload(&self, session: u64) -> Result<String, String> {
+        self.call("qr_payload", SessionParams { session })
+    }
+
+    /// Waits for the end of `session`, calling `on_state` each time its state
+    /// changes.
+    pub fn wait(&self, session: u64, mut on_state: impl FnMut(&str)) -> Result<(), String> {
+        let mut last_state = String::new();
+        loop {
+            let status = self.session_status(session)?;
+            if status.state != last_state {
+                on_state(&status.state);
+                last_state = status.state;
+            }
+            if status.done {
+                return status.error.map_or(Ok(()), Err);
+            }
+            std::thread::sleep(STATUS_POLL_INTERVAL);
+        }
+    }
+
+    pub fn list_devices(&self) -> Result<Vec<AdbDevice>, String> {
+        let devices: Vec<DeviceEntry> = self.call("list_devices", ())?;
+        devices
+            .into_iter()
+            .map(|device| {
+                Ok(AdbDevice {
+                    serial: device.serial,
+                    state: device.state.parse()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Connects the device with `serial` where it advertises, returns that
+    /// address.
+    pub fn reconnect(&self, serial: &str) -> Result<String, String> {
+        self.call(
+            "reconnect",
+            ReconnectParams {
+                serial: serial.into(),
+            },
+        )
+    }
+
+    pub fn shutdown(&self) -> Result<(), String> {
+        self.call("shutdown", ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+
+    #[test]
+    fn test_unresponsive_daemon() {
+        let path = std::env::temp_dir().join(format!(
+            "wifi-android-connect-stuck-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        // Accepts the connections, never answers
+        let _listener = UnixListener::bind(&path).unwrap();
+        let mut client = DaemonClient::new(&path);
+        client.timeout = Duration::from_millis(100);
+
+        let result = client.list_devices();
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().contains("didn't answer"));
+    }
+}
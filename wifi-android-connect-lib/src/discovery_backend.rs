@@ -0,0 +1,260 @@
+use std::{collections::HashSet, fmt, net::SocketAddrV4, str::FromStr, sync::Mutex};
+
+use crate::{
+    adb_device_authentication::AdbService,
+    adb_mdns_discovery_service::{AdbMDnsDiscoveryService, AdbServiceEvent},
+    adb_server_mdns::AdbServerMdns,
+    adb_zero_conf_mdns_sd::AdbMdns,
+    client::adb_server_address,
+    interface_filter::InterfaceFilter,
+};
+
+pub(crate) type BoxedDiscovery = Box<dyn AdbMDnsDiscoveryService + Send + Sync>;
+
+/// The mDNS implementation used to discover devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiscoveryBackend {
+    /// In-process mDNS daemon from the `mdns-sd` crate.
+    #[default]
+    MdnsSd,
+    /// The system's mDNS daemon (Avahi or Bonjour), needs the `zeroconf` feature.
+    #[cfg(feature = "zeroconf")]
+    Zeroconf,
+    /// avahi-daemon over D-Bus, needs the `avahi` feature.
+    #[cfg(feature = "avahi")]
+    Avahi,
+    /// Services seen by the adb server's own mDNS implementation.
+    AdbServer,
+}
+
+impl DiscoveryBackend {
+    /// Backends compiled into this build.
+    pub const AVAILABLE: &'static [DiscoveryBackend] = &[
+        DiscoveryBackend::MdnsSd,
+        #[cfg(feature = "zeroconf")]
+        DiscoveryBackend::Zeroconf,
+        #[cfg(feature = "avahi")]
+        DiscoveryBackend::Avahi,
+        DiscoveryBackend::AdbServer,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DiscoveryBackend::MdnsSd => "mdns-sd",
+            #[cfg(feature = "zeroconf")]
+            DiscoveryBackend::Zeroconf => "zeroconf",
+            #[cfg(feature = "avahi")]
+            DiscoveryBackend::Avahi => "avahi",
+            DiscoveryBackend::AdbServer => "adb-server",
+        }
+    }
+
+    /// Creates the backend, `interfaces` is only applied by the mdns-sd backend.
+    pub fn discovery(&self, interfaces: &InterfaceFilter) -> Result<BoxedDiscovery, String> {
+        self.discovery_on(interfaces, adb_server_address())
+    }
+
+    /// Same as [`DiscoveryBackend::discovery`], the adb server backend asks
+    /// the adb server on `server`.
+    pub(crate) fn discovery_on(
+        &self,
+        interfaces: &InterfaceFilter,
+        server: SocketAddrV4,
+    ) -> Result<BoxedDiscovery, String> {
+        if !interfaces.is_empty() && *self != DiscoveryBackend::MdnsSd {
+            log::warn!("{self} backend ignores the network interface selection");
+        }
+        match self {
+            DiscoveryBackend::MdnsSd => Ok(Box::new(AdbMdns::with_interfaces(interfaces)?)),
+            #[cfg(feature = "zeroconf")]
+            DiscoveryBackend::Zeroconf => Ok(Box::new(crate::adb_zero_conf::AdbZeroConf::new())),
+            #[cfg(feature = "avahi")]
+            DiscoveryBackend::Avahi => Ok(Box::new(crate::adb_avahi::AdbAvahi::new())),
+            DiscoveryBackend::AdbServer => Ok(Box::new(AdbServerMdns::with_server(server))),
+        }
+    }
+
+    /// Same as [`DiscoveryBackend::discovery`], but uses the adb server's mDNS
+    /// services when this backend can't be created or started.
+    pub fn discovery_with_adb_server_fallback(
+        &self,
+        interfaces: &InterfaceFilter,
+    ) -> Result<BoxedDiscovery, String> {
+        self.discovery_with_fallback_on(interfaces, adb_server_address())
+    }
+
+    /// Same as [`DiscoveryBackend::discovery_with_adb_server_fallback`], with
+    /// the adb server on `server`.
+    pub(crate) fn discovery_with_fallback_on(
+        &self,
+        interfaces: &InterfaceFilter,
+        server: SocketAddrV4,
+    ) -> Result<BoxedDiscovery, String> {
+        if *self == DiscoveryBackend::AdbServer {
+            return self.discovery_on(interfaces, server);
+        }
+
+        match self.discovery_on(interfaces, server) {
+            Ok(primary) => Ok(Box::new(FallbackDiscovery {
+                primary,
+                fallback: Box::new(AdbServerMdns::with_server(server)),
+                use_fallback: Mutex::new(false),
+            })),
+            Err(e) => {
+                log::warn!("Unable to create {self} discovery, using the adb server: {e}");
+                Ok(Box::new(AdbServerMdns::with_server(server)))
+            }
+        }
+    }
+}
+
+/// Discovers with `primary`, or `fallback` when `primary` fails to start.
+struct FallbackDiscovery {
+    primary: BoxedDiscovery,
+    fallback: BoxedDiscovery,
+    use_fallback: Mutex<bool>,
+}
+
+impl FallbackDiscovery {
+    fn active(&self) -> &BoxedDiscovery {
+        if *self.use_fallback.lock().unwrap() {
+            &self.fallback
+        } else {
+            &self.primary
+        }
+    }
+}
+
+impl AdbMDnsDiscoveryService for FallbackDiscovery {
+    fn start(&self) -> Result<(), String> {
+        let mut use_fallback = self.use_fallback.lock().unwrap();
+        match self.primary.start() {
+            Ok(()) => {
+                *use_fallback = false;
+                Ok(())
+            }
+            Err(e) => {
+                log::warn!("Unable to start discovery, using the adb server: {e}");
+                self.fallback.start()?;
+                *use_fallback = true;
+                Ok(())
+            }
+        }
+    }
+
+    fn stop(&self) -> Result<(), String> {
+        self.active().stop()
+    }
+
+    fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+        self.active().adb_tls_pairing()
+    }
+
+    fn adb_tls_connect(&self) -> HashSet<AdbService> {
+        self.active().adb_tls_connect()
+    }
+
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        self.active().events()
+    }
+}
+
+impl FromStr for DiscoveryBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(backend) = Self::AVAILABLE.iter().find(|b| b.name() == s) {
+            return Ok(*backend);
+        }
+
+        let available: Vec<_> = Self::AVAILABLE.iter().map(|b| b.name()).collect();
+        match s {
+            "zeroconf" => Err("zeroconf backend requires the zeroconf feature".into()),
+            "avahi" => Err("avahi backend requires the avahi feature".into()),
+            _ => Err(format!(
+                "Unknown backend {s}, available backends: {}",
+                available.join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DiscoveryBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adb_mdns_discovery_service::AdbServiceType;
+
+    #[test]
+    fn test_parse_backend() {
+        for backend in DiscoveryBackend::AVAILABLE {
+            assert_eq!(backend.name().parse(), Ok(*backend));
+        }
+        assert!("bonjour".parse::<DiscoveryBackend>().is_err());
+    }
+
+    struct FailingDiscovery;
+
+    impl AdbMDnsDiscoveryService for FailingDiscovery {
+        fn start(&self) -> Result<(), String> {
+            Err("multicast is not available".into())
+        }
+
+        fn stop(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+            HashSet::new()
+        }
+
+        fn adb_tls_connect(&self) -> HashSet<AdbService> {
+            HashSet::new()
+        }
+    }
+
+    struct FixedDiscovery(AdbService);
+
+    impl AdbMDnsDiscoveryService for FixedDiscovery {
+        fn start(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+            HashSet::from([self.0.clone()])
+        }
+
+        fn adb_tls_connect(&self) -> HashSet<AdbService> {
+            HashSet::new()
+        }
+    }
+
+    #[test]
+    fn test_fallback_when_primary_fails_to_start() {
+        let service = AdbService::new(
+            AdbServiceType::Pairing,
+            "studio-k3J9aZ",
+            "192.168.0.197",
+            34317,
+        );
+        let discovery = FallbackDiscovery {
+            primary: Box::new(FailingDiscovery),
+            fallback: Box::new(FixedDiscovery(service.clone())),
+            use_fallback: Mutex::new(false),
+        };
+
+        discovery.start().unwrap();
+
+        assert_eq!(discovery.adb_tls_pairing(), HashSet::from([service]));
+        assert!(discovery.stop().is_ok());
+    }
+}
@@ -0,0 +1,233 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use mdns_sd::{IfKind, ServiceDaemon};
+
+/// Matches a network interface by name (`eth0`) or by subnet (`192.168.0.0/24`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceMatcher {
+    Name(String),
+    Subnet(IpAddr, u8),
+}
+
+impl InterfaceMatcher {
+    pub fn matches(&self, name: &str, ip: &IpAddr) -> bool {
+        match self {
+            InterfaceMatcher::Name(n) => n == name,
+            InterfaceMatcher::Subnet(network, prefix) => in_subnet(network, *prefix, ip),
+        }
+    }
+}
+
+fn in_subnet(network: &IpAddr, prefix: u8, ip: &IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(*network) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(*network) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
+}
+
+impl FromStr for InterfaceMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((network, prefix)) = s.split_once('/') else {
+            if s.is_empty() {
+                return Err("Interface name should not be empty".into());
+            }
+            return Ok(InterfaceMatcher::Name(s.into()));
+        };
+
+        let network: IpAddr = network
+            .parse()
+            .map_err(|e| format!("Invalid subnet {s}: {e}"))?;
+        let prefix: u8 = prefix
+            .parse()
+            .map_err(|e| format!("Invalid subnet {s}: {e}"))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        if prefix > max_prefix {
+            return Err(format!(
+                "Invalid subnet {s}: prefix longer than {max_prefix}"
+            ));
+        }
+
+        Ok(InterfaceMatcher::Subnet(network, prefix))
+    }
+}
+
+impl fmt::Display for InterfaceMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterfaceMatcher::Name(name) => f.write_str(name),
+            InterfaceMatcher::Subnet(network, prefix) => write!(f, "{network}/{prefix}"),
+        }
+    }
+}
+
+/// Selects the network interfaces used for mDNS discovery. When nothing is
+/// included every interface is, and exclusions always win.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceFilter {
+    pub include: Vec<InterfaceMatcher>,
+    pub exclude: Vec<InterfaceMatcher>,
+}
+
+impl InterfaceFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn allows(&self, name: &str, ip: &IpAddr) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|m| m.matches(name, ip));
+        included && !self.exclude.iter().any(|m| m.matches(name, ip))
+    }
+
+    /// Applies the filter to `daemon`. Subnets are resolved to the interfaces'
+    /// current addresses, so addresses assigned later aren't filtered by subnet.
+    pub(crate) fn apply(&self, daemon: &ServiceDaemon) -> Result<(), String> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let local_ips: Vec<(String, IpAddr)> = if_addrs::get_if_addrs()
+            .map_err(|e| format!("Unable to list network interfaces: {e}"))?
+            .into_iter()
+            .map(|interface| {
+                let ip = interface.ip();
+                (interface.name, ip)
+            })
+            .collect();
+
+        // mdns-sd applies the selections in order, the last matching one wins.
+        if !self.include.is_empty() {
+            let included = self.included(&local_ips)?;
+            daemon
+                .disable_interface(IfKind::All)
+                .map_err(|e| e.to_string())?;
+            daemon
+                .enable_interface(included)
+                .map_err(|e| e.to_string())?;
+        }
+        let excluded: Vec<IfKind> = self
+            .exclude
+            .iter()
+            .flat_map(|matcher| if_kinds(matcher, &local_ips))
+            .collect();
+        if !excluded.is_empty() {
+            daemon
+                .disable_interface(excluded)
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    /// The interfaces to enable among `local_ips`, erroring when only subnets
+    /// are included and none of them is local: nothing would be discovered.
+    fn included(&self, local_ips: &[(String, IpAddr)]) -> Result<Vec<IfKind>, String> {
+        let included: Vec<IfKind> = self
+            .include
+            .iter()
+            .flat_map(|matcher| if_kinds(matcher, local_ips))
+            .collect();
+        if included.is_empty() {
+            let include: Vec<String> = self.include.iter().map(ToString::to_string).collect();
+            return Err(format!(
+                "No network interface matches {}",
+                include.join(", ")
+            ));
+        }
+        Ok(included)
+    }
+}
+
+/// What mdns-sd selects for `matcher`, subnets being resolved to `local_ips`.
+fn if_kinds(matcher: &InterfaceMatcher, local_ips: &[(String, IpAddr)]) -> Vec<IfKind> {
+    match matcher {
+        InterfaceMatcher::Name(name) => vec![IfKind::Name(name.clone())],
+        InterfaceMatcher::Subnet(..) => local_ips
+            .iter()
+            .filter(|(name, ip)| matcher.matches(name, ip))
+            .map(|(_, ip)| IfKind::Addr(*ip))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_matcher() {
+        assert_eq!("wlan0".parse(), Ok(InterfaceMatcher::Name("wlan0".into())));
+        assert_eq!(
+            "192.168.0.0/24".parse(),
+            Ok(InterfaceMatcher::Subnet(ip("192.168.0.0"), 24))
+        );
+        assert_eq!(
+            "fe80::/10".parse(),
+            Ok(InterfaceMatcher::Subnet(ip("fe80::"), 10))
+        );
+        for invalid in ["", "192.168.0.0/33", "fe80::/129", "host/24", "10.0.0.0/x"] {
+            assert!(invalid.parse::<InterfaceMatcher>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_subnet_matches() {
+        let subnet: InterfaceMatcher = "192.168.0.0/24".parse().unwrap();
+        assert!(subnet.matches("wlan0", &ip("192.168.0.197")));
+        assert!(!subnet.matches("wlan0", &ip("192.168.1.197")));
+        assert!(!subnet.matches("wlan0", &ip("fe80::1")));
+
+        let everything: InterfaceMatcher = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.matches("docker0", &ip("172.17.0.1")));
+    }
+
+    #[test]
+    fn test_filter_allows() {
+        let filter = InterfaceFilter {
+            include: vec!["192.168.0.0/16".parse().unwrap()],
+            exclude: vec!["docker0".parse().unwrap()],
+        };
+
+        assert!(filter.allows("wlan0", &ip("192.168.0.197")));
+        assert!(!filter.allows("docker0", &ip("192.168.0.1")));
+        assert!(!filter.allows("tun0", &ip("10.8.0.2")));
+        assert!(InterfaceFilter::default().allows("tun0", &ip("10.8.0.2")));
+    }
+
+    #[test]
+    fn test_included_subnet_without_interface() {
+        let local_ips = [
+            ("lo".to_string(), ip("127.0.0.1")),
+            ("wlan0".to_string(), ip("192.168.0.197")),
+        ];
+        let filter = |include: &[&str]| InterfaceFilter {
+            include: include.iter().map(|m| m.parse().unwrap()).collect(),
+            exclude: vec![],
+        };
+
+        assert_eq!(
+            filter(&["10.0.0.0/8"]).included(&local_ips).unwrap_err(),
+            "No network interface matches 10.0.0.0/8"
+        );
+        assert_eq!(
+            filter(&["10.0.0.0/8", "192.168.0.0/24"])
+                .included(&local_ips)
+                .map(|kinds| kinds.len()),
+            Ok(1)
+        );
+        // Interfaces named may come up later
+        assert!(filter(&["10.0.0.0/8", "tun0"]).included(&local_ips).is_ok());
+    }
+}
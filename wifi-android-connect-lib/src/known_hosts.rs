@@ -0,0 +1,235 @@
+//! The devices adb paired with, stored by adb in `adb_known_hosts.pb`.
+//!
+//! The file is an `AdbKnownHosts` protobuf message, a repeated `HostInfo`
+//! (field 1) whose `guid` (field 1) is `adb-<serial>`. Only those fields are
+//! read, the rest of the file is kept as is.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const KNOWN_HOSTS_FILE: &str = "adb_known_hosts.pb";
+const HOST_INFO_FIELD: u64 = 1;
+const GUID_FIELD: u64 = 1;
+const WIRE_VARINT: u64 = 0;
+const WIRE_I64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_I32: u64 = 5;
+
+/// `$ANDROID_USER_HOME/adb_known_hosts.pb`, `~/.android/adb_known_hosts.pb`
+/// when it isn't set.
+pub(crate) fn known_hosts_path() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("ANDROID_USER_HOME") {
+        return Some(PathBuf::from(dir).join(KNOWN_HOSTS_FILE));
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".android").join(KNOWN_HOSTS_FILE))
+}
+
+/// The guid adbd pairs with, from its serial.
+pub(crate) fn device_guid(serial: &str) -> String {
+    format!("adb-{serial}")
+}
+
+/// A top level field, `raw` with its tag and `payload` its length delimited
+/// content.
+struct Field<'a> {
+    number: u64,
+    payload: Option<&'a [u8]>,
+    raw: &'a [u8],
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or_else(|| "Truncated known hosts varint".to_string())?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("Invalid known hosts varint".into())
+}
+
+fn take<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], String> {
+    let end = pos
+        .checked_add(len)
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| "Truncated known hosts field".to_string())?;
+    let taken = &bytes[*pos..end];
+    *pos = end;
+    Ok(taken)
+}
+
+fn fields(bytes: &[u8]) -> Result<Vec<Field<'_>>, String> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let start = pos;
+        let tag = read_varint(bytes, &mut pos)?;
+        let payload = match tag & 0x7 {
+            WIRE_VARINT => {
+                read_varint(bytes, &mut pos)?;
+                None
+            }
+            WIRE_I64 => {
+                take(bytes, &mut pos, 8)?;
+                None
+            }
+            WIRE_LEN => {
+                let len = read_varint(bytes, &mut pos)?;
+                let len = usize::try_from(len).map_err(|e| e.to_string())?;
+                Some(take(bytes, &mut pos, len)?)
+            }
+            WIRE_I32 => {
+                take(bytes, &mut pos, 4)?;
+                None
+            }
+            wire => return Err(format!("Unsupported known hosts wire type {wire}")),
+        };
+        fields.push(Field {
+            number: tag >> 3,
+            payload,
+            raw: &bytes[start..pos],
+        });
+    }
+    Ok(fields)
+}
+
+fn guid(host_info: &[u8]) -> Result<Option<String>, String> {
+    let guid = fields(host_info)?
+        .into_iter()
+        .find(|field| field.number == GUID_FIELD)
+        .and_then(|field| field.payload);
+    guid.map(|guid| String::from_utf8(guid.to_vec()).map_err(|e| e.to_string()))
+        .transpose()
+}
+
+/// The guids of the paired devices in `known_hosts`.
+fn parse_guids(known_hosts: &[u8]) -> Result<Vec<String>, String> {
+    let mut guids = Vec::new();
+    for field in fields(known_hosts)? {
+        if let (HOST_INFO_FIELD, Some(host_info)) = (field.number, field.payload) {
+            guids.extend(guid(host_info)?);
+        }
+    }
+    Ok(guids)
+}
+
+/// `known_hosts` without the host infos of `guid`, `None` when it has none.
+fn remove_guid(known_hosts: &[u8], guid_to_remove: &str) -> Result<Option<Vec<u8>>, String> {
+    let mut kept = Vec::with_capacity(known_hosts.len());
+    let mut removed = false;
+    for field in fields(known_hosts)? {
+        if let (HOST_INFO_FIELD, Some(host_info)) = (field.number, field.payload) {
+            if guid(host_info)?.as_deref() == Some(guid_to_remove) {
+                removed = true;
+                continue;
+            }
+        }
+        kept.extend_from_slice(field.raw);
+    }
+    Ok(removed.then_some(kept))
+}
+
+/// Serials of the devices paired in the `path` known hosts, none when adb
+/// never paired.
+pub(crate) fn paired_serials(path: &Path) -> Result<Vec<String>, String> {
+    let known_hosts = match fs::read(path) {
+        Ok(known_hosts) => known_hosts,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Unable to read {}: {e}", path.display())),
+    };
+    Ok(parse_guids(&known_hosts)?
+        .into_iter()
+        .filter_map(|guid| guid.strip_prefix("adb-").map(str::to_string))
+        .collect())
+}
+
+/// Removes the device with `serial` from the `path` known hosts, so adb stops
+/// connecting to it on its own. The device still trusts this host.
+pub(crate) fn forget(path: &Path, serial: &str) -> Result<(), String> {
+    let guid = device_guid(serial);
+    let not_paired = || format!("{serial} isn't paired");
+    let known_hosts = match fs::read(path) {
+        Ok(known_hosts) => known_hosts,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_paired()),
+        Err(e) => return Err(format!("Unable to read {}: {e}", path.display())),
+    };
+    let kept = remove_guid(&known_hosts, &guid)?.ok_or_else(not_paired)?;
+
+    // Written aside then renamed, so adb never reads half a file
+    let tmp = path.with_extension("pb.tmp");
+    fs::write(&tmp, kept).map_err(|e| format!("Unable to write {}: {e}", tmp.display()))?;
+    fs::rename(&tmp, path).map_err(|e| format!("Unable to write {}: {e}", path.display()))?;
+    log::info!("removed {serial} from adb's known hosts");
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An `AdbKnownHosts` message with `guids`.
+    pub(crate) fn known_hosts(guids: &[&str]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for guid in guids {
+            let host_info = [&[0x0a, guid.len() as u8], guid.as_bytes()].concat();
+            bytes.extend([0x0a, host_info.len() as u8]);
+            bytes.extend(host_info);
+        }
+        bytes
+    }
+
+    /// A known hosts file in a directory of its own.
+    pub(crate) fn known_hosts_file(name: &str, guids: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "wifi-android-connect-{name}-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(KNOWN_HOSTS_FILE);
+        fs::write(&path, known_hosts(guids)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_forget() {
+        let path = known_hosts_file("forget", &["adb-wg858lj7t959helz", "adb-R58M123ABC"]);
+
+        assert_eq!(forget(&path, "wg858lj7t959helz"), Ok(()));
+        assert_eq!(paired_serials(&path), Ok(vec!["R58M123ABC".to_string()]));
+        assert_eq!(
+            forget(&path, "wg858lj7t959helz"),
+            Err("wg858lj7t959helz isn't paired".into())
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_forget_keeps_unknown_fields() {
+        // A varint field 2 and a host info with an extra field 2
+        let mut bytes = vec![0x10, 0x96, 0x01];
+        bytes.extend(known_hosts(&["adb-wg858lj7t959helz"]));
+        bytes.extend([
+            0x0a, 0x0a, 0x0a, 0x03, b'a', b'd', b'b', 0x12, 0x03, b'k', b'e', b'y',
+        ]);
+
+        let kept = remove_guid(&bytes, "adb-wg858lj7t959helz")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(kept[..3], [0x10, 0x96, 0x01]);
+        assert_eq!(parse_guids(&kept), Ok(vec!["adb".to_string()]));
+        assert_eq!(remove_guid(&kept, "adb-wg858lj7t959helz"), Ok(None));
+    }
+
+    #[test]
+    fn test_truncated_known_hosts() {
+        assert!(parse_guids(&[0x0a, 0x05, 0x0a]).is_err());
+    }
+}
@@ -1,90 +1,330 @@
+#[cfg(feature = "avahi")]
+mod adb_avahi;
+mod adb_device;
 mod adb_device_authentication;
+mod adb_instance_name;
+mod adb_server_mdns;
 #[cfg(feature = "zeroconf")]
 mod adb_zero_conf;
 mod adb_zero_conf_mdns_sd;
+mod blocking;
+mod builder;
 mod client;
+#[cfg(all(unix, feature = "daemon"))]
+mod daemon;
+#[cfg(all(unix, feature = "daemon"))]
+mod daemon_client;
+mod discovery_backend;
+mod interface_filter;
+mod known_hosts;
+mod pair_code;
+#[cfg(feature = "record")]
+mod session_record;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+mod thread_adb_client;
+mod timer;
+#[cfg(feature = "tokio")]
+mod tokio_adb_client;
+mod watchdog;
+mod wifi_qrcode;
 
 mod adb_mdns_discovery_service;
 
-use adb_device_authentication::AdbDeviceAuthentication;
-use adb_mdns_discovery_service::AdbMDnsDiscoveryService;
-use adb_zero_conf_mdns_sd::AdbMdns;
-use client::{AdbClient, RustAdbClient};
+use std::{
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use adb_device_authentication::{AdbDeviceAuthentication, StateObserver};
 use qrcode::{render::unicode, QrCode};
-use rand::Rng;
+use rand::{distributions::Alphanumeric, Rng};
+use wifi_qrcode::wifi_connect_msg;
 
-fn wifi_connect_msg(name: &str, pair_code: u32) -> Result<String, String> {
-    if !(100_000..999_999).contains(&pair_code) {
-        return Err("Pair code should be a 6 digits number".into());
-    }
-    Ok(format!(
-        "WIFI:T:ADB;S:{hostname};P:{password};;",
-        hostname = name,
-        password = pair_code
-    ))
-}
+#[cfg(feature = "avahi")]
+pub use adb_avahi::AdbAvahi;
+pub use adb_device::{AdbDevice, TransportState};
+pub use adb_device_authentication::{AdbConnectionState, AdbService, AdbStateTransition};
+pub use adb_instance_name::AdbInstanceName;
+pub use adb_mdns_discovery_service::{
+    AdbMDnsDiscoveryService, AdbServiceEvent, AdbServiceType, AsyncAdbMDnsDiscoveryService,
+};
+pub use adb_server_mdns::AdbServerMdns;
+#[cfg(feature = "zeroconf")]
+pub use adb_zero_conf::AdbZeroConf;
+pub use adb_zero_conf_mdns_sd::AdbMdns;
+pub use blocking::Blocking;
+pub use builder::WifiAndroidConnectBuilder;
+pub use client::{AdbClient, AsyncAdbClient, RustAdbClient};
+#[cfg(all(unix, feature = "daemon"))]
+pub use daemon::{default_socket_path, Daemon, PairingRequest, PairingSession, SessionStatus};
+#[cfg(all(unix, feature = "daemon"))]
+pub use daemon_client::DaemonClient;
+pub use discovery_backend::DiscoveryBackend;
+pub use interface_filter::{InterfaceFilter, InterfaceMatcher};
+pub use pair_code::PairCode;
+#[cfg(feature = "record")]
+pub use session_record::{RecordedEntry, SessionEntry, SessionRecorder, SessionReplay};
+pub use thread_adb_client::ThreadAdbClient;
+pub use timer::Timer;
+#[cfg(feature = "tokio")]
+pub use timer::TokioTimer;
+#[cfg(feature = "tokio")]
+pub use tokio_adb_client::TokioAdbClient;
+pub use watchdog::WatchEvent;
+pub use wifi_qrcode::AdbQrPayload;
 
-fn generate_qrcode_img(data: String) -> String {
-    let code = QrCode::new(data).unwrap();
-    code.render::<unicode::Dense1x2>()
+fn generate_qrcode_img(data: String) -> Result<String, String> {
+    let code = QrCode::new(data).map_err(|e| format!("Unable to create the QR code: {e}"))?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
         .dark_color(unicode::Dense1x2::Light)
         .light_color(unicode::Dense1x2::Dark)
-        .build()
+        .build())
+}
+
+const DEFAULT_PAIR_NAME: &str = "WIFI Android Connect";
+/// How often [`WifiAndroidConnect::connect`] looks at the discovered services.
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often [`WifiAndroidConnect::watch`] checks the adb transports.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+/// How often it looks for discovery events in between.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long [`WifiAndroidConnect::async_connect`] waits for a discovery event
+/// before retrying what failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Appends a random token to `prefix`, so two sessions running on the same
+/// network don't advertise the same pairing service name.
+fn session_pair_name(prefix: &str) -> String {
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(6)
+        .map(char::from)
+        .collect();
+    format!("{prefix}-{token}")
 }
 
-fn random_6_digits_pair_code() -> u32 {
-    rand::thread_rng().gen_range(100_000..999_999)
+enum AsyncDiscovery {
+    Native(AdbMdns),
+    Polled(discovery_backend::BoxedDiscovery),
 }
 
 pub struct WifiAndroidConnect {
-    pub pair_name: String,
-    pub pair_code: u32,
+    pair_name: String,
+    /// What the pair name starts with, the other sessions' names do too.
+    pair_name_prefix: String,
+    pair_code: PairCode,
+    timeout: Option<Duration>,
+    backend: DiscoveryBackend,
+    adb_server_fallback: bool,
+    interfaces: InterfaceFilter,
+    adb_server: SocketAddrV4,
+    observers: Vec<StateObserver>,
+    #[cfg(feature = "record")]
+    record: Option<std::path::PathBuf>,
 }
 
 impl Default for WifiAndroidConnect {
     fn default() -> Self {
         Self {
-            pair_name: "WIFI Android Connect".into(),
-            pair_code: random_6_digits_pair_code(),
+            pair_name: session_pair_name(DEFAULT_PAIR_NAME),
+            pair_name_prefix: DEFAULT_PAIR_NAME.into(),
+            pair_code: PairCode::random(),
+            timeout: None,
+            backend: DiscoveryBackend::default(),
+            adb_server_fallback: true,
+            interfaces: InterfaceFilter::default(),
+            adb_server: client::adb_server_address(),
+            observers: Vec::new(),
+            #[cfg(feature = "record")]
+            record: None,
         }
     }
 }
 
 impl WifiAndroidConnect {
-    pub fn new(pair_name: String, pair_code: u32) -> Self {
-        WifiAndroidConnect {
-            pair_name,
-            pair_code,
-        }
+    pub fn builder() -> WifiAndroidConnectBuilder {
+        WifiAndroidConnectBuilder::new()
+    }
+    pub fn pair_name(&self) -> &str {
+        &self.pair_name
+    }
+    pub fn pair_code(&self) -> &PairCode {
+        &self.pair_code
+    }
+    pub fn backend(&self) -> DiscoveryBackend {
+        self.backend
+    }
+    pub fn adb_server(&self) -> SocketAddrV4 {
+        self.adb_server
+    }
+    /// The `WIFI:T:ADB;S:<name>;P:<code>;;` text encoded in the QR code.
+    pub fn qrcode_payload(&self) -> Result<String, String> {
+        wifi_connect_msg(&self.pair_name, &self.pair_code)
     }
     pub fn qrcode_img(&self) -> Result<String, String> {
-        let code = wifi_connect_msg(&self.pair_name, self.pair_code)?;
-        Ok(generate_qrcode_img(code))
+        generate_qrcode_img(self.qrcode_payload()?)
     }
     pub fn connect(&self) -> Result<(), String> {
-        let mdns = AdbMdns::new()?;
-        let mut auth = AdbDeviceAuthentication::new(self.pair_code, self.pair_name.clone());
+        let mdns = self.discovery()?;
+        let client = self.client();
+        #[cfg(feature = "record")]
+        if let Some(path) = &self.record {
+            let recorder = self.recorder(path, mdns.as_ref(), &client)?;
+            return self.connect_with(&recorder, &recorder);
+        }
+        self.connect_with(mdns.as_ref(), &client)
+    }
+
+    /// Closes the connection to the device at `address`, e.g.
+    /// `192.168.0.197:34003`.
+    pub fn disconnect(&self, address: &str) -> Result<(), String> {
+        self.client().adb_disconnect(address)
+    }
+
+    /// The devices known by the adb server and the state of their transport.
+    pub fn list_devices(&self) -> Result<Vec<AdbDevice>, String> {
+        self.client().list_devices()
+    }
+
+    /// Disconnects the device with `serial` and stops adb connecting to it
+    /// when it's discovered, see [`AdbClient::forget`] for what it doesn't
+    /// do.
+    pub fn forget(&self, serial: &str) -> Result<(), String> {
+        self.client().forget(serial)
+    }
+
+    /// Keeps the devices adb paired with connected, reconnecting them when
+    /// their transport drops or they advertise on another port. Runs until
+    /// the timeout, forever without one.
+    pub fn watch(&self, on_event: impl FnMut(&WatchEvent)) -> Result<(), String> {
+        let mdns = self.discovery()?;
+        self.watch_with(mdns.as_ref(), &self.client(), on_event)
+    }
+
+    /// Same as [`WifiAndroidConnect::watch`], but discovers the devices with
+    /// `mdns` and reconnects them with `client`.
+    pub fn watch_with(
+        &self,
+        mdns: &(impl AdbMDnsDiscoveryService + ?Sized),
+        client: &impl AdbClient,
+        mut on_event: impl FnMut(&WatchEvent),
+    ) -> Result<(), String> {
+        let paired = known_hosts::known_hosts_path()
+            .map(|path| known_hosts::paired_serials(&path))
+            .transpose()
+            .unwrap_or_else(|e| {
+                log::warn!("{e}, only watching the devices connected from now on");
+                None
+            });
+        let mut watchdog = watchdog::Watchdog::new(paired.into_iter().flatten());
+
+        mdns.start()?;
+        let deadline = self.deadline();
+        let mut next_check = Instant::now();
+
+        while !Self::is_expired(deadline) {
+            let changed = !mdns.events().is_empty();
+            if changed || Instant::now() >= next_check {
+                if let Err(e) = watchdog.check(&mdns.adb_tls_connect(), client, &mut on_event) {
+                    log::warn!("Unable to check the devices: {e}");
+                }
+                next_check = Instant::now() + WATCH_INTERVAL;
+            }
+            std::thread::sleep(WATCH_POLL_INTERVAL);
+        }
+
+        mdns.stop()
+    }
+
+    /// Runs a [`Daemon`] on `socket` until a client asks for its shutdown,
+    /// discovering devices like [`WifiAndroidConnect::connect`] does.
+    #[cfg(all(unix, feature = "daemon"))]
+    pub fn daemon(&self, socket: impl AsRef<std::path::Path>) -> Result<(), String> {
+        let timeout = self.timeout.unwrap_or(daemon::DEFAULT_SESSION_TIMEOUT);
+        let discovery = std::sync::Arc::from(self.discovery()?);
+        Daemon::bind(socket, discovery, self.client(), timeout)?.run()
+    }
+
+    #[cfg(feature = "record")]
+    fn recorder<'a>(
+        &self,
+        path: &std::path::Path,
+        mdns: &'a (dyn AdbMDnsDiscoveryService + Send + Sync),
+        client: &'a RustAdbClient,
+    ) -> Result<SessionRecorder<'a>, String> {
+        SessionRecorder::create(path, &self.pair_name, &self.pair_code, mdns, client)
+    }
+
+    /// Same as [`WifiAndroidConnect::connect`], but discovers the devices with
+    /// `mdns` and pairs/connects them with `client`.
+    pub fn connect_with(
+        &self,
+        mdns: &(impl AdbMDnsDiscoveryService + ?Sized),
+        client: &impl AdbClient,
+    ) -> Result<(), String> {
+        let mut auth = self.authentication();
 
         mdns.start()?;
-        let client = RustAdbClient;
+        let deadline = self.deadline();
 
-        loop {
-            if self.iter(&mut auth, &mdns, &client) {
-                break;
+        let result = loop {
+            if self.iter(&mut auth, mdns, client) {
+                break Ok(());
+            }
+            if Self::is_expired(deadline) {
+                break Err("Timeout".to_string());
             }
+            std::thread::sleep(CONNECT_POLL_INTERVAL);
+        };
+
+        // Report the connection error first, stopping failures come after
+        let stopped = mdns.stop();
+        result.and(stopped)
+    }
+
+    fn discovery(&self) -> Result<discovery_backend::BoxedDiscovery, String> {
+        if self.adb_server_fallback {
+            self.backend
+                .discovery_with_fallback_on(&self.interfaces, self.adb_server)
+        } else {
+            self.backend.discovery_on(&self.interfaces, self.adb_server)
         }
+    }
+
+    fn client(&self) -> RustAdbClient {
+        RustAdbClient::with_server(self.adb_server)
+    }
+
+    fn authentication(&self) -> AdbDeviceAuthentication {
+        let mut auth = AdbDeviceAuthentication::new(self.pair_code, self.pair_name.clone())
+            .with_name_prefix(&self.pair_name_prefix);
+        for observer in &self.observers {
+            auth.add_observer(observer.clone());
+        }
+        auth
+    }
 
-        mdns.stop()?;
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
 
-        Ok(())
+    fn is_expired(deadline: Option<Instant>) -> bool {
+        deadline.is_some_and(|deadline| Instant::now() >= deadline)
     }
 
     fn iter(
         &self,
         auth: &mut AdbDeviceAuthentication,
-        mdns: &impl AdbMDnsDiscoveryService,
+        mdns: &(impl AdbMDnsDiscoveryService + ?Sized),
         client: &impl AdbClient,
     ) -> bool {
+        for event in mdns.events() {
+            log::debug!("discovery event {event:?}");
+            auth.on_service_event(&event);
+        }
+
         let pair_set = mdns.adb_tls_pairing();
         let connect_set = mdns.adb_tls_connect();
 
@@ -103,42 +343,509 @@ impl WifiAndroidConnect {
 
     #[cfg(feature = "tokio")]
     pub async fn async_connect(&self) -> Result<(), String> {
-        let mdns = AdbMdns::new()?;
-        let mut auth = AdbDeviceAuthentication::new(self.pair_code, self.pair_name.clone());
+        #[cfg(feature = "record")]
+        if let Some(path) = &self.record {
+            let mdns = self.discovery()?;
+            return self
+                .async_connect_recording(path, mdns.as_ref(), &TokioTimer)
+                .await;
+        }
+        let client = TokioAdbClient::with_server(self.adb_server);
+        match self.async_discovery()? {
+            AsyncDiscovery::Native(mdns) => {
+                self.async_connect_with(&mdns, &client, &TokioTimer).await
+            }
+            AsyncDiscovery::Polled(mdns) => {
+                self.async_connect_with(&Blocking::new(mdns.as_ref()), &client, &TokioTimer)
+                    .await
+            }
+        }
+    }
 
-        mdns.start()?;
-        let client = RustAdbClient;
+    /// Same as [`WifiAndroidConnect::async_connect`], on any async runtime:
+    /// `timer` sleeps on it and the adb calls run on threads of their own,
+    /// see [`ThreadAdbClient`]. Recording still blocks the task's thread.
+    pub async fn async_connect_with_timer(&self, timer: &impl Timer) -> Result<(), String> {
+        #[cfg(feature = "record")]
+        if let Some(path) = &self.record {
+            let mdns = self.discovery()?;
+            return self
+                .async_connect_recording(path, mdns.as_ref(), timer)
+                .await;
+        }
+        let client = ThreadAdbClient::with_server(self.adb_server);
+        match self.async_discovery()? {
+            AsyncDiscovery::Native(mdns) => self.async_connect_with(&mdns, &client, timer).await,
+            AsyncDiscovery::Polled(mdns) => {
+                let mdns = Blocking::with_timer(mdns.as_ref(), timer);
+                self.async_connect_with(&mdns, &client, timer).await
+            }
+        }
+    }
 
-        loop {
-            if self.iter(&mut auth, &mdns, &client) {
-                break;
+    /// The discovery of the async API: mdns-sd waits for its events, the other
+    /// backends are polled through [`Blocking`].
+    fn async_discovery(&self) -> Result<AsyncDiscovery, String> {
+        if self.backend != DiscoveryBackend::MdnsSd {
+            return self.discovery().map(AsyncDiscovery::Polled);
+        }
+        match AdbMdns::with_interfaces(&self.interfaces) {
+            Ok(mdns) => Ok(AsyncDiscovery::Native(mdns)),
+            Err(e) if self.adb_server_fallback => {
+                log::warn!("Unable to create mdns-sd discovery, using the adb server: {e}");
+                DiscoveryBackend::AdbServer
+                    .discovery_on(&self.interfaces, self.adb_server)
+                    .map(AsyncDiscovery::Polled)
             }
-            tokio::task::yield_now().await;
+            Err(e) => Err(e),
         }
+    }
 
-        mdns.stop()?;
+    #[cfg(feature = "record")]
+    async fn async_connect_recording(
+        &self,
+        path: &std::path::Path,
+        mdns: &(dyn AdbMDnsDiscoveryService + Send + Sync),
+        timer: &impl Timer,
+    ) -> Result<(), String> {
+        let client = self.client();
+        let recorder = self.recorder(path, mdns, &client)?;
+        let recorder = Blocking::with_timer(&recorder, timer);
+        self.async_connect_with(&recorder, &recorder, timer).await
+    }
 
-        Ok(())
+    /// Same as [`WifiAndroidConnect::async_connect_with_timer`], but discovers
+    /// the devices with `mdns` and pairs/connects them with `client`.
+    pub async fn async_connect_with(
+        &self,
+        mdns: &impl AsyncAdbMDnsDiscoveryService,
+        client: &impl AsyncAdbClient,
+        timer: &impl Timer,
+    ) -> Result<(), String> {
+        let mut auth = self.authentication();
+
+        mdns.start().await?;
+        let deadline = self.deadline();
+
+        let result = loop {
+            if self.async_iter(&mut auth, mdns, client).await {
+                break Ok(());
+            }
+            if Self::is_expired(deadline) {
+                break Err("Timeout".to_string());
+            }
+            let wait = deadline.map_or(RETRY_INTERVAL, |deadline| {
+                RETRY_INTERVAL.min(deadline.saturating_duration_since(Instant::now()))
+            });
+            match timer::timeout(timer, wait, mdns.next_event()).await {
+                Some(Some(event)) => {
+                    log::debug!("discovery event {event:?}");
+                    auth.on_service_event(&event);
+                }
+                Some(None) => break Err("Discovery stopped".to_string()),
+                // Nothing changed, retry what failed
+                None => {}
+            }
+        };
+
+        // Report the connection error first, stopping failures come after
+        let stopped = mdns.stop().await;
+        result.and(stopped)
+    }
+
+    async fn async_iter(
+        &self,
+        auth: &mut AdbDeviceAuthentication,
+        mdns: &impl AsyncAdbMDnsDiscoveryService,
+        client: &impl AsyncAdbClient,
+    ) -> bool {
+        for service in &mdns.adb_tls_pairing() {
+            log::trace!("on pair {auth:?} {service:?}");
+            auth.on_pair_async(service, client).await;
+        }
+
+        for service in &mdns.adb_tls_connect() {
+            log::trace!("on connect {auth:?} {service:?}");
+            auth.on_connect_async(service, client).await;
+        }
+
+        auth.is_connected()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use super::*;
 
+    #[derive(Default)]
+    struct MockDiscovery {
+        pairing: HashSet<AdbService>,
+        connect: HashSet<AdbService>,
+    }
+
+    impl AdbMDnsDiscoveryService for MockDiscovery {
+        fn start(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+            self.pairing.clone()
+        }
+
+        fn adb_tls_connect(&self) -> HashSet<AdbService> {
+            self.connect.clone()
+        }
+    }
+
+    /// Fails at the selected steps, to check failures are reported instead of
+    /// panicking.
+    #[derive(Default)]
+    struct FaultyDiscovery {
+        fail_start: bool,
+        fail_stop: bool,
+        services: MockDiscovery,
+    }
+
+    impl AdbMDnsDiscoveryService for FaultyDiscovery {
+        fn start(&self) -> Result<(), String> {
+            if self.fail_start {
+                return Err("injected start failure".into());
+            }
+            self.services.start()
+        }
+
+        fn stop(&self) -> Result<(), String> {
+            if self.fail_stop {
+                return Err("injected stop failure".into());
+            }
+            self.services.stop()
+        }
+
+        fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+            self.services.adb_tls_pairing()
+        }
+
+        fn adb_tls_connect(&self) -> HashSet<AdbService> {
+            self.services.adb_tls_connect()
+        }
+    }
+
+    struct FailingClient;
+
+    impl AdbClient for FailingClient {
+        fn adb_pair(&self, _address: &str, _code: &PairCode) -> Result<(), String> {
+            Err("injected pair failure".into())
+        }
+
+        fn adb_connect(&self, _address: &str) -> Result<(), String> {
+            Err("injected connect failure".into())
+        }
+    }
+
+    struct SuccessMock;
+
+    impl AdbClient for SuccessMock {
+        fn adb_pair(&self, _address: &str, _code: &PairCode) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn adb_connect(&self, _address: &str) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    fn service(service_type: AdbServiceType, name: &str, port: u16) -> AdbService {
+        AdbService::new(service_type, name, "192.168.0.197", port)
+    }
+
+    #[test]
+    fn test_connect_with() {
+        let conn = WifiAndroidConnect::builder()
+            .pair_name("test")
+            .build()
+            .unwrap();
+        let mdns = MockDiscovery {
+            pairing: HashSet::from([service(
+                AdbServiceType::Pairing,
+                "test._adb-tls-pairing._tcp.local.",
+                34317,
+            )]),
+            connect: HashSet::from([service(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            )]),
+        };
+
+        assert_eq!(conn.connect_with(&mdns, &SuccessMock), Ok(()));
+    }
+
+    #[test]
+    fn test_connect_with_timeout() {
+        let conn = WifiAndroidConnect::builder()
+            .pair_name("test")
+            .timeout(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        let mdns = MockDiscovery {
+            pairing: HashSet::new(),
+            connect: HashSet::new(),
+        };
+
+        assert_eq!(
+            conn.connect_with(&mdns, &SuccessMock),
+            Err("Timeout".to_string())
+        );
+    }
+
+    fn test_connection(timeout: Duration) -> WifiAndroidConnect {
+        WifiAndroidConnect::builder()
+            .pair_name("test")
+            .timeout(timeout)
+            .build()
+            .unwrap()
+    }
+
+    fn reachable_device() -> MockDiscovery {
+        MockDiscovery {
+            pairing: HashSet::from([service(AdbServiceType::Pairing, "test", 34317)]),
+            connect: HashSet::from([service(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            )]),
+        }
+    }
+
+    #[test]
+    fn test_start_failure_is_reported() {
+        let conn = test_connection(Duration::from_millis(10));
+        let mdns = FaultyDiscovery {
+            fail_start: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            conn.connect_with(&mdns, &SuccessMock),
+            Err("injected start failure".to_string())
+        );
+    }
+
+    #[test]
+    fn test_stop_failure_is_reported() {
+        let conn = test_connection(Duration::from_millis(10));
+        let mdns = FaultyDiscovery {
+            fail_stop: true,
+            services: reachable_device(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            conn.connect_with(&mdns, &SuccessMock),
+            Err("injected stop failure".to_string())
+        );
+    }
+
     #[test]
-    fn test_wifi_msg_wrong_digits() {
-        for code in [1, 12, 123, 1234, 12345, 1234567] {
-            let msg = wifi_connect_msg("connectAndroid", code);
-            assert!(msg.is_err())
+    fn test_connect_failure_is_reported_before_stop_failure() {
+        let conn = test_connection(Duration::from_millis(10));
+        let mdns = FaultyDiscovery {
+            fail_stop: true,
+            services: reachable_device(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            conn.connect_with(&mdns, &FailingClient),
+            Err("Timeout".to_string())
+        );
+    }
+
+    /// Only accepts connections once paired, like a device seeing a new host.
+    #[derive(Default)]
+    struct PairingClient {
+        paired: std::cell::RefCell<Vec<String>>,
+        connected: std::cell::RefCell<Vec<String>>,
+    }
+
+    impl AdbClient for PairingClient {
+        fn adb_pair(&self, address: &str, _code: &PairCode) -> Result<(), String> {
+            self.paired.borrow_mut().push(address.into());
+            Ok(())
+        }
+
+        fn adb_connect(&self, address: &str) -> Result<(), String> {
+            if self.paired.borrow().is_empty() {
+                return Err("failed to authenticate".into());
+            }
+            self.connected.borrow_mut().push(address.into());
+            Ok(())
         }
     }
 
     #[test]
-    fn test_wifi_msg_6_digit() {
-        let msg = wifi_connect_msg("connectAndroid", 765912).unwrap();
-        assert_eq!(msg, "WIFI:T:ADB;S:connectAndroid;P:765912;;");
-        let msg = wifi_connect_msg("connect Android", 123456).unwrap();
-        assert_eq!(msg, "WIFI:T:ADB;S:connect Android;P:123456;;");
+    fn test_connect_over_loopback_mdns() {
+        use test_support::{loopback_interfaces, FakeMdnsResponder};
+
+        let conn = test_connection(Duration::from_secs(10));
+        let responder = FakeMdnsResponder::loopback().unwrap();
+        responder
+            .advertise(AdbServiceType::Pairing, conn.pair_name(), 34317)
+            .unwrap();
+        responder
+            .advertise(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            )
+            .unwrap();
+        let mdns = AdbMdns::with_interfaces(&loopback_interfaces()).unwrap();
+        let client = PairingClient::default();
+
+        assert_eq!(conn.connect_with(&mdns, &client), Ok(()));
+        assert_eq!(*client.paired.borrow(), ["127.0.0.1:34317"]);
+        assert_eq!(*client.connected.borrow(), ["127.0.0.1:34003"]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_async_connect_over_loopback_mdns() {
+        use test_support::{loopback_interfaces, FakeAdbServer, FakeDevice, FakeMdnsResponder};
+
+        let conn = test_connection(Duration::from_secs(10));
+        let server = FakeAdbServer::start().unwrap();
+        server.add_device(FakeDevice {
+            pairing_address: "127.0.0.1:34317".into(),
+            pair_code: *conn.pair_code(),
+            connect_address: "127.0.0.1:34003".into(),
+        });
+        let responder = FakeMdnsResponder::loopback().unwrap();
+        responder
+            .advertise(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            )
+            .unwrap();
+        let mdns = AdbMdns::with_interfaces(&loopback_interfaces()).unwrap();
+        let client = TokioAdbClient::with_server(server.address());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let result = std::thread::scope(|scope| {
+            // The pairing dialog opens after the connect service is known
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(200));
+                responder
+                    .advertise(AdbServiceType::Pairing, conn.pair_name(), 34317)
+                    .unwrap();
+            });
+            runtime.block_on(conn.async_connect_with(&mdns, &client, &TokioTimer))
+        });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(server.paired(), ["127.0.0.1:34317"]);
+        assert_eq!(server.connected(), ["127.0.0.1:34003"]);
+    }
+
+    #[test]
+    fn test_async_connect_without_tokio() {
+        use test_support::{loopback_interfaces, FakeAdbServer, FakeDevice, FakeMdnsResponder};
+        use timer::tests::ThreadTimer;
+
+        let conn = test_connection(Duration::from_secs(10));
+        let server = FakeAdbServer::start().unwrap();
+        server.add_device(FakeDevice {
+            pairing_address: "127.0.0.1:34317".into(),
+            pair_code: *conn.pair_code(),
+            connect_address: "127.0.0.1:34003".into(),
+        });
+        let responder = FakeMdnsResponder::loopback().unwrap();
+        responder
+            .advertise(AdbServiceType::Pairing, conn.pair_name(), 34317)
+            .unwrap();
+        responder
+            .advertise(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            )
+            .unwrap();
+        let mdns = AdbMdns::with_interfaces(&loopback_interfaces()).unwrap();
+        let client = ThreadAdbClient::with_server(server.address());
+
+        let result =
+            futures::executor::block_on(conn.async_connect_with(&mdns, &client, &ThreadTimer));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(server.connected(), ["127.0.0.1:34003"]);
+    }
+
+    #[test]
+    fn test_watch_reconnects_dropped_device() {
+        use test_support::{loopback_interfaces, FakeAdbServer, FakeDevice, FakeMdnsResponder};
+
+        let conn = test_connection(Duration::from_secs(6));
+        let server = FakeAdbServer::start().unwrap();
+        server.add_device(FakeDevice {
+            pairing_address: "127.0.0.1:34317".into(),
+            pair_code: *conn.pair_code(),
+            connect_address: "127.0.0.1:34003".into(),
+        });
+        let client = RustAdbClient::with_server(server.address());
+        client
+            .adb_pair("127.0.0.1:34317", conn.pair_code())
+            .unwrap();
+        client.adb_connect("127.0.0.1:34003").unwrap();
+        let responder = FakeMdnsResponder::loopback().unwrap();
+        responder
+            .advertise(
+                AdbServiceType::Connect,
+                "adb-wg858lj7t959helz-si5LWZ",
+                34003,
+            )
+            .unwrap();
+        let mdns = AdbMdns::with_interfaces(&loopback_interfaces()).unwrap();
+        let mut events = Vec::new();
+
+        let result = std::thread::scope(|scope| {
+            // The phone goes to sleep once the watchdog saw it
+            scope.spawn(|| {
+                while AdbMDnsDiscoveryService::adb_tls_connect(&mdns).is_empty() {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                std::thread::sleep(WATCH_POLL_INTERVAL * 2);
+                client.adb_disconnect("127.0.0.1:34003").unwrap();
+            });
+            conn.watch_with(&mdns, &client, |event| events.push(event.clone()))
+        });
+
+        assert_eq!(result, Ok(()));
+        assert!(
+            events.contains(&WatchEvent::Reconnected {
+                serial: "wg858lj7t959helz".into(),
+                address: "127.0.0.1:34003".into(),
+            }),
+            "{events:?}"
+        );
+        assert_eq!(server.connected(), ["127.0.0.1:34003"]);
+    }
+
+    #[test]
+    fn test_session_pair_name_is_unique() {
+        let first = session_pair_name(DEFAULT_PAIR_NAME);
+        let second = session_pair_name(DEFAULT_PAIR_NAME);
+
+        assert!(first.starts_with("WIFI Android Connect-"));
+        assert_ne!(first, second);
+        assert!(wifi_qrcode::validate_service_name(&first).is_ok());
     }
 }
@@ -0,0 +1,87 @@
+use std::{fmt, str::FromStr};
+
+use rand::{rngs::OsRng, Rng};
+
+const PAIR_CODE_LEN: usize = 6;
+
+/// The 6 digits code used to pair with a device, leading zeros included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PairCode([u8; PAIR_CODE_LEN]);
+
+impl PairCode {
+    /// Generates a pair code using the operating system's secure random generator.
+    pub fn random() -> PairCode {
+        let mut digits = [0; PAIR_CODE_LEN];
+        for digit in &mut digits {
+            *digit = OsRng.gen_range(b'0'..=b'9');
+        }
+        PairCode(digits)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII digits are accepted on construction
+        std::str::from_utf8(&self.0).expect("pair code should be ASCII digits")
+    }
+}
+
+impl FromStr for PairCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: [u8; PAIR_CODE_LEN] = s
+            .as_bytes()
+            .try_into()
+            .map_err(|_| format!("Pair code should be a 6 digits number, got {s}"))?;
+
+        if !digits.iter().all(u8::is_ascii_digit) {
+            return Err(format!("Pair code should be a 6 digits number, got {s}"));
+        }
+
+        Ok(PairCode(digits))
+    }
+}
+
+impl fmt::Display for PairCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrong_digits() {
+        for code in [
+            "",
+            "1",
+            "12",
+            "123",
+            "1234",
+            "12345",
+            "1234567",
+            "12a456",
+            " 12345",
+            "١٢٣٤٥٦",
+        ] {
+            assert!(code.parse::<PairCode>().is_err(), "{code:?}");
+        }
+    }
+
+    #[test]
+    fn test_6_digits() {
+        for code in ["000000", "012345", "123456", "999999"] {
+            let pair_code: PairCode = code.parse().unwrap();
+            assert_eq!(pair_code.to_string(), code);
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        for _ in 0..100 {
+            let code = PairCode::random();
+            assert_eq!(code.to_string().parse::<PairCode>(), Ok(code));
+        }
+    }
+}
@@ -0,0 +1,528 @@
+//! Records what a discovery backend reports and the adb client calls made
+//! during a session, one JSON entry per line, so the session can be replayed
+//! deterministically, e.g. to reproduce services arriving in an odd order.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, LineWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adb_device_authentication::AdbService,
+    adb_mdns_discovery_service::{AdbMDnsDiscoveryService, AdbServiceEvent},
+    client::AdbClient,
+    pair_code::PairCode,
+};
+
+/// A call made during a session and what it returned.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum SessionEntry {
+    /// The pairing service name and pair code of the session, always first.
+    Session {
+        pair_name: String,
+        pair_code: String,
+    },
+    Start {
+        result: Result<(), String>,
+    },
+    Stop {
+        result: Result<(), String>,
+    },
+    /// Only recorded when there were events.
+    Events {
+        events: Vec<AdbServiceEvent>,
+    },
+    /// Only recorded when the pairing services changed.
+    Pairing {
+        services: Vec<AdbService>,
+    },
+    /// Only recorded when the connect services changed.
+    Connect {
+        services: Vec<AdbService>,
+    },
+    AdbPair {
+        address: String,
+        code: String,
+        result: Result<(), String>,
+    },
+    AdbConnect {
+        address: String,
+        result: Result<(), String>,
+    },
+}
+
+/// A line of a recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedEntry {
+    /// Time since the recording started, replays ignore it.
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub entry: SessionEntry,
+}
+
+fn sorted(services: &HashSet<AdbService>) -> Vec<AdbService> {
+    let mut services: Vec<_> = services.iter().cloned().collect();
+    services.sort_by(|a, b| (&a.name, a.port).cmp(&(&b.name, b.port)));
+    services
+}
+
+struct RecorderState {
+    out: Box<dyn Write + Send>,
+    started: Instant,
+    pairing: Option<HashSet<AdbService>>,
+    connect: Option<HashSet<AdbService>>,
+}
+
+impl RecorderState {
+    /// Failing to record doesn't fail the session, it's only logged.
+    fn record(&mut self, entry: SessionEntry) {
+        let entry = RecordedEntry {
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            entry,
+        };
+        let written = serde_json::to_string(&entry)
+            .map_err(|e| e.to_string())
+            .and_then(|line| writeln!(self.out, "{line}").map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            log::warn!("Unable to record {entry:?}: {e}");
+        }
+    }
+}
+
+/// Discovers with `discovery` and pairs/connects with `client`, recording
+/// every call.
+pub struct SessionRecorder<'a> {
+    discovery: &'a (dyn AdbMDnsDiscoveryService + Send + Sync + 'a),
+    client: &'a (dyn AdbClient + Send + Sync + 'a),
+    state: Mutex<RecorderState>,
+}
+
+impl<'a> SessionRecorder<'a> {
+    pub fn new(
+        pair_name: &str,
+        pair_code: &PairCode,
+        discovery: &'a (dyn AdbMDnsDiscoveryService + Send + Sync + 'a),
+        client: &'a (dyn AdbClient + Send + Sync + 'a),
+        out: impl Write + Send + 'static,
+    ) -> SessionRecorder<'a> {
+        let mut state = RecorderState {
+            out: Box::new(out),
+            started: Instant::now(),
+            pairing: None,
+            connect: None,
+        };
+        state.record(SessionEntry::Session {
+            pair_name: pair_name.to_string(),
+            pair_code: pair_code.to_string(),
+        });
+        SessionRecorder {
+            discovery,
+            client,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Records to the file at `path`, replacing it.
+    pub fn create(
+        path: impl AsRef<Path>,
+        pair_name: &str,
+        pair_code: &PairCode,
+        discovery: &'a (dyn AdbMDnsDiscoveryService + Send + Sync + 'a),
+        client: &'a (dyn AdbClient + Send + Sync + 'a),
+    ) -> Result<SessionRecorder<'a>, String> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).map_err(|e| format!("Unable to create {}: {e}", path.display()))?;
+        Ok(Self::new(
+            pair_name,
+            pair_code,
+            discovery,
+            client,
+            LineWriter::new(file),
+        ))
+    }
+
+    fn record(&self, entry: SessionEntry) {
+        self.state.lock().unwrap().record(entry);
+    }
+}
+
+impl AdbMDnsDiscoveryService for SessionRecorder<'_> {
+    fn start(&self) -> Result<(), String> {
+        let result = self.discovery.start();
+        self.record(SessionEntry::Start {
+            result: result.clone(),
+        });
+        result
+    }
+
+    fn stop(&self) -> Result<(), String> {
+        let result = self.discovery.stop();
+        self.record(SessionEntry::Stop {
+            result: result.clone(),
+        });
+        result
+    }
+
+    fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+        let services = self.discovery.adb_tls_pairing();
+        let mut state = self.state.lock().unwrap();
+        if state.pairing.as_ref() != Some(&services) {
+            state.record(SessionEntry::Pairing {
+                services: sorted(&services),
+            });
+            state.pairing = Some(services.clone());
+        }
+        services
+    }
+
+    fn adb_tls_connect(&self) -> HashSet<AdbService> {
+        let services = self.discovery.adb_tls_connect();
+        let mut state = self.state.lock().unwrap();
+        if state.connect.as_ref() != Some(&services) {
+            state.record(SessionEntry::Connect {
+                services: sorted(&services),
+            });
+            state.connect = Some(services.clone());
+        }
+        services
+    }
+
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        let events = self.discovery.events();
+        if !events.is_empty() {
+            self.record(SessionEntry::Events {
+                events: events.clone(),
+            });
+        }
+        events
+    }
+}
+
+impl AdbClient for SessionRecorder<'_> {
+    fn adb_pair(&self, address: &str, code: &PairCode) -> Result<(), String> {
+        let result = self.client.adb_pair(address, code);
+        self.record(SessionEntry::AdbPair {
+            address: address.to_string(),
+            code: code.to_string(),
+            result: result.clone(),
+        });
+        result
+    }
+
+    fn adb_connect(&self, address: &str) -> Result<(), String> {
+        let result = self.client.adb_connect(address);
+        self.record(SessionEntry::AdbConnect {
+            address: address.to_string(),
+            result: result.clone(),
+        });
+        result
+    }
+}
+
+struct ReplayState {
+    entries: Vec<SessionEntry>,
+    cursor: usize,
+    pairing: HashSet<AdbService>,
+    connect: HashSet<AdbService>,
+}
+
+impl ReplayState {
+    /// Consumes the next entry if it `matches`.
+    fn next_if(&mut self, matches: impl FnOnce(&SessionEntry) -> bool) -> Option<SessionEntry> {
+        let entry = self
+            .entries
+            .get(self.cursor)
+            .filter(|e| matches(e))?
+            .clone();
+        self.cursor += 1;
+        Some(entry)
+    }
+
+    fn diverged(&self, call: &str) -> String {
+        let msg = match self.entries.get(self.cursor) {
+            Some(expected) => format!("Replay diverged: {call} instead of {expected:?}"),
+            None => format!("Replay diverged: {call} after the end of the recording"),
+        };
+        log::error!("{msg}");
+        msg
+    }
+}
+
+/// Plays a recorded session back, as both the discovery backend and the adb
+/// client.
+///
+/// Entries are replayed in order, regardless of their timestamps: services
+/// change when the next entry is a change of these services, and client calls
+/// return their recorded result when they're the next entry. Client calls
+/// that weren't recorded there fail.
+pub struct SessionReplay {
+    pair_name: String,
+    pair_code: PairCode,
+    state: Mutex<ReplayState>,
+}
+
+impl SessionReplay {
+    pub fn new(entries: Vec<SessionEntry>) -> Result<SessionReplay, String> {
+        let mut entries = entries.into_iter();
+        let Some(SessionEntry::Session {
+            pair_name,
+            pair_code,
+        }) = entries.next()
+        else {
+            return Err("Recording should start with the session entry".into());
+        };
+
+        Ok(SessionReplay {
+            pair_name,
+            pair_code: pair_code.parse()?,
+            state: Mutex::new(ReplayState {
+                entries: entries.collect(),
+                cursor: 0,
+                pairing: HashSet::new(),
+                connect: HashSet::new(),
+            }),
+        })
+    }
+
+    /// Parses a recording, one [`RecordedEntry`] per line.
+    pub fn parse(recording: impl BufRead) -> Result<SessionReplay, String> {
+        let mut entries = vec![];
+        for (number, line) in recording.lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: RecordedEntry = serde_json::from_str(&line)
+                .map_err(|e| format!("Invalid recording line {}: {e}", number + 1))?;
+            entries.push(entry.entry);
+        }
+        Self::new(entries)
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<SessionReplay, String> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).map_err(|e| format!("Unable to open {}: {e}", path.display()))?;
+        Self::parse(BufReader::new(file))
+    }
+
+    /// The pairing service name of the recorded session.
+    pub fn pair_name(&self) -> &str {
+        &self.pair_name
+    }
+
+    pub fn pair_code(&self) -> &PairCode {
+        &self.pair_code
+    }
+
+    /// Whether every recorded entry was replayed.
+    pub fn is_finished(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.cursor == state.entries.len()
+    }
+}
+
+impl AdbMDnsDiscoveryService for SessionReplay {
+    fn start(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        match state.next_if(|e| matches!(e, SessionEntry::Start { .. })) {
+            Some(SessionEntry::Start { result }) => result,
+            _ => Ok(()),
+        }
+    }
+
+    fn stop(&self) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        match state.next_if(|e| matches!(e, SessionEntry::Stop { .. })) {
+            Some(SessionEntry::Stop { result }) => result,
+            _ => Ok(()),
+        }
+    }
+
+    fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(SessionEntry::Pairing { services }) =
+            state.next_if(|e| matches!(e, SessionEntry::Pairing { .. }))
+        {
+            state.pairing = services.into_iter().collect();
+        }
+        state.pairing.clone()
+    }
+
+    fn adb_tls_connect(&self) -> HashSet<AdbService> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(SessionEntry::Connect { services }) =
+            state.next_if(|e| matches!(e, SessionEntry::Connect { .. }))
+        {
+            state.connect = services.into_iter().collect();
+        }
+        state.connect.clone()
+    }
+
+    fn events(&self) -> Vec<AdbServiceEvent> {
+        let mut state = self.state.lock().unwrap();
+        match state.next_if(|e| matches!(e, SessionEntry::Events { .. })) {
+            Some(SessionEntry::Events { events }) => events,
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl AdbClient for SessionReplay {
+    fn adb_pair(&self, address: &str, code: &PairCode) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let recorded = state.next_if(|e| {
+            matches!(e, SessionEntry::AdbPair { address: a, code: c, .. }
+                if a == address && c == code.as_str())
+        });
+        match recorded {
+            Some(SessionEntry::AdbPair { result, .. }) => result,
+            _ => Err(state.diverged(&format!("adb pair {address}"))),
+        }
+    }
+
+    fn adb_connect(&self, address: &str) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let recorded = state
+            .next_if(|e| matches!(e, SessionEntry::AdbConnect { address: a, .. } if a == address));
+        match recorded {
+            Some(SessionEntry::AdbConnect { result, .. }) => result,
+            _ => Err(state.diverged(&format!("adb connect {address}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use super::*;
+    use crate::{adb_mdns_discovery_service::AdbServiceType, WifiAndroidConnect};
+
+    const PAIR_NAME: &str = "WIFI Android Connect-k3J9aZ";
+
+    /// Reports the connect service a round before the pairing service.
+    #[derive(Default)]
+    struct ConnectFirstDiscovery {
+        round: Mutex<usize>,
+    }
+
+    fn pairing() -> AdbService {
+        AdbService::new(AdbServiceType::Pairing, PAIR_NAME, "192.168.0.197", 34317)
+    }
+
+    fn connect() -> AdbService {
+        AdbService::new(
+            AdbServiceType::Connect,
+            "adb-wg858lj7t959helz-si5LWZ",
+            "192.168.0.197",
+            34003,
+        )
+    }
+
+    impl AdbMDnsDiscoveryService for ConnectFirstDiscovery {
+        fn start(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn adb_tls_pairing(&self) -> HashSet<AdbService> {
+            match *self.round.lock().unwrap() {
+                0 => HashSet::new(),
+                _ => HashSet::from([pairing()]),
+            }
+        }
+
+        fn adb_tls_connect(&self) -> HashSet<AdbService> {
+            *self.round.lock().unwrap() += 1;
+            HashSet::from([connect()])
+        }
+    }
+
+    struct AcceptingClient;
+
+    impl AdbClient for AcceptingClient {
+        fn adb_pair(&self, _address: &str, _code: &PairCode) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn adb_connect(&self, _address: &str) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    fn connection(pair_name: &str, pair_code: PairCode) -> WifiAndroidConnect {
+        WifiAndroidConnect::builder()
+            .pair_name(pair_name)
+            .pair_code(pair_code)
+            .timeout(Duration::from_secs(1))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("wifi-session-{}.jsonl", std::process::id()));
+        let conn = connection(PAIR_NAME, "024680".parse().unwrap());
+        let discovery = ConnectFirstDiscovery::default();
+        let recorder = SessionRecorder::create(
+            &path,
+            PAIR_NAME,
+            conn.pair_code(),
+            &discovery,
+            &AcceptingClient,
+        )
+        .unwrap();
+        assert_eq!(conn.connect_with(&recorder, &recorder), Ok(()));
+        drop(recorder);
+
+        let replay = SessionReplay::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let replay = replay.unwrap();
+        let conn = connection(replay.pair_name(), *replay.pair_code());
+
+        assert_eq!(conn.connect_with(&replay, &replay), Ok(()));
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn test_replay_recording() {
+        let recording = r#"{"elapsed_ms":0,"call":"session","pair_name":"studio-k3J9aZ","pair_code":"012345"}
+{"elapsed_ms":1,"call":"start","result":{"Ok":null}}
+{"elapsed_ms":2,"call":"pairing","services":[]}
+{"elapsed_ms":2,"call":"connect","services":[]}
+{"elapsed_ms":40,"call":"adb_connect","address":"192.168.0.197:34003","result":{"Err":"failed to authenticate"}}
+"#;
+        let replay = SessionReplay::parse(recording.as_bytes()).unwrap();
+
+        assert_eq!(replay.pair_name(), "studio-k3J9aZ");
+        assert_eq!(replay.pair_code().as_str(), "012345");
+        assert_eq!(replay.start(), Ok(()));
+        assert!(replay.adb_tls_pairing().is_empty());
+        assert!(replay.adb_tls_connect().is_empty());
+        let diverged = replay.adb_pair("192.168.0.197:34317", replay.pair_code());
+        assert!(diverged.unwrap_err().starts_with("Replay diverged"));
+        assert_eq!(
+            replay.adb_connect("192.168.0.197:34003"),
+            Err("failed to authenticate".into())
+        );
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn test_replay_needs_session() {
+        let recording = r#"{"elapsed_ms":1,"call":"start","result":{"Ok":null}}"#;
+        assert!(SessionReplay::parse(recording.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+//! Fake devices advertising adb services over mDNS on the loopback interface,
+//! and a fake adb server, so discovery, pairing and connection can be tested
+//! without a network, a phone or adb.
+
+use std::{
+    collections::BTreeSet,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use mdns_sd::{IfKind, ServiceDaemon, ServiceInfo};
+
+use crate::{
+    adb_mdns_discovery_service::AdbServiceType,
+    interface_filter::{InterfaceFilter, InterfaceMatcher},
+    pair_code::PairCode,
+};
+
+const FAKE_HOSTNAME: &str = "fake-android.local.";
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Only uses the loopback interface, where [`FakeMdnsResponder`] advertises.
+pub fn loopback_interfaces() -> InterfaceFilter {
+    InterfaceFilter {
+        include: vec![InterfaceMatcher::Subnet(Ipv4Addr::LOCALHOST.into(), 8)],
+        exclude: vec![],
+    }
+}
+
+/// An mDNS responder advertising adb services, on `127.0.0.1` unless
+/// created with another address.
+pub struct FakeMdnsResponder {
+    daemon: ServiceDaemon,
+    ip: Ipv4Addr,
+}
+
+impl FakeMdnsResponder {
+    pub fn loopback() -> Result<FakeMdnsResponder, String> {
+        Self::new(Ipv4Addr::LOCALHOST)
+    }
+
+    /// Advertises services on `ip`, which should be an address of this host,
+    /// on the loopback interface only for a loopback address.
+    pub fn new(ip: Ipv4Addr) -> Result<FakeMdnsResponder, String> {
+        let daemon = ServiceDaemon::new().map_err(|e| e.to_string())?;
+        if ip.is_loopback() {
+            daemon
+                .disable_interface(IfKind::All)
+                .map_err(|e| e.to_string())?;
+            daemon
+                .enable_interface(IfKind::LoopbackV4)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(FakeMdnsResponder { daemon, ip })
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ip.into()
+    }
+
+    /// Advertises `instance_name` as a `service_type` service on `port`,
+    /// returning the service's full name.
+    pub fn advertise(
+        &self,
+        service_type: AdbServiceType,
+        instance_name: &str,
+        port: u16,
+    ) -> Result<String, String> {
+        let info = ServiceInfo::new(
+            &format!("{}.local.", service_type.as_str()),
+            instance_name,
+            FAKE_HOSTNAME,
+            self.ip(),
+            port,
+            &[("v", "1")][..],
+        )
+        .map_err(|e| e.to_string())?;
+        let fullname = info.get_fullname().to_string();
+
+        self.daemon.register(info).map_err(|e| e.to_string())?;
+        Ok(fullname)
+    }
+
+    /// Stops advertising the service `fullname`, like a device turning
+    /// wireless debugging off.
+    pub fn withdraw(&self, fullname: &str) -> Result<(), String> {
+        self.daemon
+            .unregister(fullname)
+            .map_err(|e| e.to_string())?
+            .recv_timeout(UNREGISTER_TIMEOUT)
+            .map_err(|e| format!("Unable to withdraw {fullname}: {e}"))?;
+        Ok(())
+    }
+}
+
+impl Drop for FakeMdnsResponder {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// A fake adb server reply to a host service request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdbReply {
+    /// `OKAY` followed by a message, e.g. `already connected to ...`.
+    Okay(String),
+    /// `FAIL` followed by an error message.
+    Fail(String),
+}
+
+/// A phone behind a [`FakeAdbServer`], only pairing with its pair code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeDevice {
+    /// Address of the `_adb-tls-pairing._tcp` service.
+    pub pairing_address: String,
+    pub pair_code: PairCode,
+    /// Address of the `_adb-tls-connect._tcp` service.
+    pub connect_address: String,
+}
+
+#[derive(Debug, Default)]
+struct FakeAdbState {
+    /// Scripted replies as (request prefix, reply), the last one matching wins.
+    replies: Vec<(String, AdbReply)>,
+    devices: Vec<FakeDevice>,
+    requests: Vec<String>,
+    paired: BTreeSet<String>,
+    connected: BTreeSet<String>,
+}
+
+impl FakeAdbState {
+    fn reply(&mut self, request: &str) -> AdbReply {
+        self.requests.push(request.to_string());
+        if let Some((_, reply)) = self
+            .replies
+            .iter()
+            .rev()
+            .find(|(prefix, _)| request.starts_with(prefix.as_str()))
+        {
+            return reply.clone();
+        }
+
+        if let Some(pair) = request.strip_prefix("host:pair:") {
+            match pair.split_once(':') {
+                Some((code, address)) => self.pair(code, address),
+                None => AdbReply::Fail(format!("malformed pair request {request}")),
+            }
+        } else if let Some(address) = request.strip_prefix("host:connect:") {
+            self.connect(address)
+        } else if let Some(address) = request.strip_prefix("host:disconnect:") {
+            self.disconnect(address)
+        } else if request == "host:devices" {
+            AdbReply::Okay(
+                self.connected
+                    .iter()
+                    .map(|address| format!("{address}\tdevice\n"))
+                    .collect(),
+            )
+        } else if request == "host:mdns:services" {
+            AdbReply::Okay(String::new())
+        } else {
+            AdbReply::Fail(format!("unknown host service {request}"))
+        }
+    }
+
+    /// Pairs with any code when no device was added.
+    fn pair(&mut self, code: &str, address: &str) -> AdbReply {
+        if !self.devices.is_empty() {
+            match self.devices.iter().find(|d| d.pairing_address == address) {
+                None => return AdbReply::Okay("Failed: Unable to start pairing client.".into()),
+                Some(device) if device.pair_code.as_str() != code => {
+                    return AdbReply::Okay(
+                        "Failed: Wrong password or connection was dropped.".into(),
+                    )
+                }
+                Some(_) => {}
+            }
+        }
+        self.paired.insert(address.to_string());
+        AdbReply::Okay(format!("Successfully paired to {address}"))
+    }
+
+    /// Disconnects everything when `address` is empty, like adb.
+    fn disconnect(&mut self, address: &str) -> AdbReply {
+        if address.is_empty() {
+            self.connected.clear();
+            AdbReply::Okay("disconnected everything".into())
+        } else if self.connected.remove(address) {
+            AdbReply::Okay(format!("disconnected {address}"))
+        } else {
+            AdbReply::Fail(format!("no such device '{address}'"))
+        }
+    }
+
+    /// Connects to any address when no device was added.
+    fn connect(&mut self, address: &str) -> AdbReply {
+        if !self.devices.is_empty() {
+            match self.devices.iter().find(|d| d.connect_address == address) {
+                None => {
+                    return AdbReply::Okay(format!(
+                        "failed to connect to '{address}': Connection refused"
+                    ))
+                }
+                Some(device) if !self.paired.contains(&device.pairing_address) => {
+                    return AdbReply::Okay(format!("failed to authenticate to {address}"))
+                }
+                Some(_) => {}
+            }
+        }
+        if self.connected.insert(address.to_string()) {
+            AdbReply::Okay(format!("connected to {address}"))
+        } else {
+            AdbReply::Okay(format!("already connected to {address}"))
+        }
+    }
+}
+
+/// An adb server on a local TCP port speaking the smart socket protocol.
+///
+/// Without scripted replies it behaves like adb with a device accepting any
+/// pair code: `host:pair` succeeds, `host:connect` connects, then answers
+/// `already connected`, `host:disconnect` disconnects, and `host:devices`
+/// lists the connected addresses.
+/// Once devices are added, only their addresses and pair codes are accepted.
+pub struct FakeAdbServer {
+    address: SocketAddrV4,
+    state: Arc<Mutex<FakeAdbState>>,
+    running: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl FakeAdbServer {
+    /// Listens on a free loopback port.
+    pub fn start() -> Result<FakeAdbServer, String> {
+        Self::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+    }
+
+    pub fn bind(address: SocketAddrV4) -> Result<FakeAdbServer, String> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| format!("Unable to listen on {address}: {e}"))?;
+        let address = match listener.local_addr().map_err(|e| e.to_string())? {
+            std::net::SocketAddr::V4(address) => address,
+            address => return Err(format!("Unexpected listening address {address}")),
+        };
+        let state = Arc::new(Mutex::new(FakeAdbState::default()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let worker = {
+            let state = state.clone();
+            let running = running.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = serve(stream, &state) {
+                                log::warn!("Fake adb server: {e}");
+                            }
+                        }
+                        Err(e) => log::warn!("Fake adb server: {e}"),
+                    }
+                }
+            })
+        };
+
+        Ok(FakeAdbServer {
+            address,
+            state,
+            running,
+            worker: Some(worker),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    /// Replies `reply` to the requests starting with `request_prefix`, e.g.
+    /// `host:pair` or `host:connect:192.168.0.197:34003`.
+    pub fn reply(&self, request_prefix: &str, reply: AdbReply) {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .push((request_prefix.to_string(), reply));
+    }
+
+    /// The requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn add_device(&self, device: FakeDevice) {
+        self.state.lock().unwrap().devices.push(device);
+    }
+
+    /// The pairing addresses paired so far.
+    pub fn paired(&self) -> Vec<String> {
+        self.state.lock().unwrap().paired.iter().cloned().collect()
+    }
+
+    /// The addresses connected so far.
+    pub fn connected(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .connected
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Answers the single host service request sent on `stream`.
+fn serve(mut stream: TcpStream, state: &Mutex<FakeAdbState>) -> Result<(), String> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length).map_err(|e| e.to_string())?;
+    let length = std::str::from_utf8(&length).map_err(|e| e.to_string())?;
+    let length = usize::from_str_radix(length, 16).map_err(|e| e.to_string())?;
+    let mut request = vec![0; length];
+    stream.read_exact(&mut request).map_err(|e| e.to_string())?;
+    let request = String::from_utf8(request).map_err(|e| e.to_string())?;
+
+    let (status, message) = match state.lock().unwrap().reply(&request) {
+        AdbReply::Okay(message) => ("OKAY", message),
+        AdbReply::Fail(message) => ("FAIL", message),
+    };
+    stream
+        .write_all(format!("{status}{:04x}{message}", message.len()).as_bytes())
+        .map_err(|e| e.to_string())
+}
+
+impl Drop for FakeAdbServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        // Wakes the worker blocked in accept
+        let _ = TcpStream::connect(self.address);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
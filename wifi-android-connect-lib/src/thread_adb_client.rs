@@ -0,0 +1,135 @@
+use std::{
+    future::poll_fn,
+    net::SocketAddrV4,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+};
+
+use crate::{
+    client::{adb_server_address, AdbClient, AsyncAdbClient, RustAdbClient},
+    pair_code::PairCode,
+};
+
+/// [`AsyncAdbClient`] running each request of a [`RustAdbClient`] on a
+/// thread of its own, so it works on any async runtime without blocking it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadAdbClient {
+    client: RustAdbClient,
+}
+
+impl ThreadAdbClient {
+    /// Client of the adb server listening on `127.0.0.1:5037`, or on
+    /// `ADB_SERVER_SOCKET` when it's set.
+    pub fn new() -> ThreadAdbClient {
+        Self::with_server(adb_server_address())
+    }
+
+    /// Client of the adb server listening on `server`, which isn't started
+    /// when it isn't running.
+    pub fn with_server(server: SocketAddrV4) -> ThreadAdbClient {
+        ThreadAdbClient {
+            client: RustAdbClient::with_server(server),
+        }
+    }
+}
+
+impl Default for ThreadAdbClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Request<T> {
+    result: Option<Result<T, String>>,
+    waker: Option<Waker>,
+}
+
+/// Runs `f` on a new thread, waking the awaiting task when it returns.
+async fn on_thread<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    let request = Arc::new(Mutex::new(Request {
+        result: None,
+        waker: None,
+    }));
+    let worker = request.clone();
+    std::thread::spawn(move || {
+        let result = std::panic::catch_unwind(AssertUnwindSafe(f))
+            .unwrap_or_else(|_| Err("adb request panicked".into()));
+        let mut request = worker.lock().unwrap();
+        request.result = Some(result);
+        if let Some(waker) = request.waker.take() {
+            waker.wake();
+        }
+    });
+
+    poll_fn(|cx| {
+        let mut request = request.lock().unwrap();
+        match request.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                request.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+impl AsyncAdbClient for ThreadAdbClient {
+    async fn adb_pair(&self, address: &str, code: &PairCode) -> Result<(), String> {
+        let (client, address, code) = (self.client, address.to_string(), *code);
+        on_thread(move || client.adb_pair(&address, &code)).await
+    }
+
+    async fn adb_connect(&self, address: &str) -> Result<(), String> {
+        let (client, address) = (self.client, address.to_string());
+        on_thread(move || client.adb_connect(&address)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::test_support::{AdbReply, FakeAdbServer, FakeDevice};
+
+    const DEVICE: &str = "192.168.0.197:34003";
+
+    #[test]
+    fn test_pair_and_connect() {
+        let server = FakeAdbServer::start().unwrap();
+        server.add_device(FakeDevice {
+            pairing_address: "192.168.0.197:34317".into(),
+            pair_code: "012345".parse().unwrap(),
+            connect_address: DEVICE.into(),
+        });
+        let client = ThreadAdbClient::with_server(server.address());
+
+        block_on(async {
+            assert!(client.adb_connect(DEVICE).await.is_err(), "not paired yet");
+            assert_eq!(
+                client
+                    .adb_pair("192.168.0.197:34317", &"012345".parse().unwrap())
+                    .await,
+                Ok(())
+            );
+            assert_eq!(client.adb_connect(DEVICE).await, Ok(()));
+        });
+        assert_eq!(server.requests()[1], "host:pair:012345:192.168.0.197:34317");
+    }
+
+    #[test]
+    fn test_server_failure() {
+        let server = FakeAdbServer::start().unwrap();
+        server.reply("host:", AdbReply::Fail("unknown host service".into()));
+        let client = ThreadAdbClient::with_server(server.address());
+
+        assert_eq!(
+            block_on(client.adb_connect(DEVICE)),
+            Err("unknown host service".into())
+        );
+    }
+}
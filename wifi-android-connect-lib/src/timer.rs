@@ -0,0 +1,116 @@
+use std::{
+    future::{poll_fn, Future},
+    pin::pin,
+    task::Poll,
+    time::Duration,
+};
+
+/// Sleeps on the async runtime the connection is awaited from, so the async
+/// API doesn't depend on a given executor.
+///
+/// ```
+/// # use std::{future::Future, time::Duration};
+/// # use wifi_android_connect_lib::Timer;
+/// # mod async_std { pub mod task { pub async fn sleep(_: std::time::Duration) {} } }
+/// struct AsyncStdTimer;
+///
+/// impl Timer for AsyncStdTimer {
+///     fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+///         async_std::task::sleep(duration)
+///     }
+/// }
+/// ```
+pub trait Timer: Sync {
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send;
+}
+
+impl<T: Timer + ?Sized> Timer for &T {
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+        (**self).sleep(duration)
+    }
+}
+
+/// [`Timer`] of the tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+        tokio::time::sleep(duration)
+    }
+}
+
+/// Output of `future`, `None` when it didn't complete within `duration`.
+pub(crate) async fn timeout<F: Future>(
+    timer: &impl Timer,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut elapsed = pin!(timer.sleep(duration));
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        elapsed.as_mut().poll(cx).map(|()| None)
+    })
+    .await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::Waker,
+        thread,
+    };
+
+    use super::*;
+
+    /// [`Timer`] waking the task from a thread, independent of any runtime.
+    pub(crate) struct ThreadTimer;
+
+    impl Timer for ThreadTimer {
+        fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + Send {
+            let done = Arc::new(AtomicBool::new(false));
+            let mut started = false;
+            poll_fn(move |cx| {
+                if done.load(Ordering::SeqCst) {
+                    return Poll::Ready(());
+                }
+                if !started {
+                    started = true;
+                    let done = done.clone();
+                    let waker: Waker = cx.waker().clone();
+                    thread::spawn(move || {
+                        thread::sleep(duration);
+                        done.store(true, Ordering::SeqCst);
+                        waker.wake();
+                    });
+                }
+                Poll::Pending
+            })
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let timer = ThreadTimer;
+
+        let completed =
+            futures::executor::block_on(timeout(&timer, Duration::from_secs(10), async { 42 }));
+        let elapsed = futures::executor::block_on(timeout(
+            &timer,
+            Duration::from_millis(10),
+            std::future::pending::<()>(),
+        ));
+
+        assert_eq!(completed, Some(42));
+        assert_eq!(elapsed, None);
+    }
+}
@@ -0,0 +1,171 @@
+use std::net::SocketAddrV4;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    client::{
+        adb_server_address, connect_reply, connect_request, encode_request, host_reply, pair_reply,
+        pair_request, parse_device_address, parse_hex_length, parse_status, server_started,
+        should_start_server, AsyncAdbClient, ADB_SERVER_ADDRESS,
+    },
+    pair_code::PairCode,
+};
+
+/// [`AsyncAdbClient`] talking to an adb server over its smart socket
+/// protocol, with tokio sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokioAdbClient {
+    server: SocketAddrV4,
+}
+
+impl TokioAdbClient {
+    /// Client of the adb server listening on `127.0.0.1:5037`, or on
+    /// `ADB_SERVER_SOCKET` when it's set.
+    pub fn new() -> TokioAdbClient {
+        TokioAdbClient {
+            server: adb_server_address(),
+        }
+    }
+
+    /// Client of the adb server listening on `server`, which isn't started
+    /// when it isn't running.
+    pub fn with_server(server: SocketAddrV4) -> TokioAdbClient {
+        TokioAdbClient { server }
+    }
+}
+
+impl Default for TokioAdbClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsyncAdbClient for TokioAdbClient {
+    async fn adb_pair(&self, address: &str, code: &PairCode) -> Result<(), String> {
+        let ipv4 = parse_device_address(address)?;
+        pair_reply(adb_host_request(self.server, &pair_request(ipv4, code)).await)
+    }
+
+    async fn adb_connect(&self, address: &str) -> Result<(), String> {
+        let ipv4 = parse_device_address(address)?;
+        connect_reply(
+            address,
+            adb_host_request(self.server, &connect_request(ipv4)).await,
+        )
+    }
+}
+
+async fn adb_server_stream(server: SocketAddrV4) -> Result<TcpStream, String> {
+    match TcpStream::connect(server).await {
+        Ok(stream) => return Ok(stream),
+        Err(e) => should_start_server(server, e)?,
+    }
+    server_started(
+        tokio::process::Command::new("adb")
+            .arg("start-server")
+            .status()
+            .await,
+    )?;
+    TcpStream::connect(ADB_SERVER_ADDRESS)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn read_message(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
+    let mut length = [0; 4];
+    stream
+        .read_exact(&mut length)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut msg = vec![0; parse_hex_length(&length)?];
+    stream
+        .read_exact(&mut msg)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(msg)
+}
+
+/// Same as [`crate::client::adb_host_request`], without blocking.
+async fn adb_host_request(server: SocketAddrV4, request: &str) -> Result<String, String> {
+    let mut stream = adb_server_stream(server).await?;
+    stream
+        .write_all(encode_request(request).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut status = [0; 4];
+    stream
+        .read_exact(&mut status)
+        .await
+        .map_err(|e| e.to_string())?;
+    let accepted = parse_status(&status)?;
+    host_reply(accepted, read_message(&mut stream).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{AdbReply, FakeAdbServer, FakeDevice};
+
+    const DEVICE: &str = "192.168.0.197:34003";
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_pair_and_connect() {
+        let server = FakeAdbServer::start().unwrap();
+        server.add_device(FakeDevice {
+            pairing_address: "192.168.0.197:34317".into(),
+            pair_code: "012345".parse().unwrap(),
+            connect_address: DEVICE.into(),
+        });
+        let client = TokioAdbClient::with_server(server.address());
+
+        block_on(async {
+            assert!(client.adb_connect(DEVICE).await.is_err(), "not paired yet");
+            assert_eq!(
+                client
+                    .adb_pair("192.168.0.197:34317", &"012345".parse().unwrap())
+                    .await,
+                Ok(())
+            );
+            assert_eq!(client.adb_connect(DEVICE).await, Ok(()));
+            assert_eq!(
+                client.adb_connect(DEVICE).await,
+                Ok(()),
+                "already connected"
+            );
+        });
+        assert_eq!(server.requests()[1], "host:pair:012345:192.168.0.197:34317");
+    }
+
+    #[test]
+    fn test_server_failure() {
+        let server = FakeAdbServer::start().unwrap();
+        server.reply("host:", AdbReply::Fail("unknown host service".into()));
+        let client = TokioAdbClient::with_server(server.address());
+
+        assert_eq!(
+            block_on(client.adb_connect(DEVICE)),
+            Err("unknown host service".into())
+        );
+    }
+
+    #[test]
+    fn test_server_unreachable() {
+        let address = FakeAdbServer::start().unwrap().address();
+
+        let result = block_on(TokioAdbClient::with_server(address).adb_connect(DEVICE));
+
+        assert!(result.unwrap_err().contains("Unable to reach adb server"));
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use crate::{
+    adb_device::{AdbDevice, TransportState},
+    adb_device_authentication::AdbService,
+    client::AdbClient,
+};
+
+/// What [`crate::WifiAndroidConnect::watch`] did to keep a device connected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The device's transport went away, e.g. the phone went to sleep.
+    Disconnected {
+        serial: String,
+    },
+    /// The device advertised its connect service on another port.
+    Moved {
+        serial: String,
+        address: String,
+    },
+    Reconnected {
+        serial: String,
+        address: String,
+    },
+    /// Not retried until the device advertises elsewhere, or stops
+    /// advertising and comes back.
+    ReconnectFailed {
+        serial: String,
+        address: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchEvent::Disconnected { serial } => write!(f, "{serial} disconnected"),
+            WatchEvent::Moved { serial, address } => write!(f, "{serial} moved to {address}"),
+            WatchEvent::Reconnected { serial, address } => {
+                write!(f, "{serial} reconnected on {address}")
+            }
+            WatchEvent::ReconnectFailed {
+                serial,
+                address,
+                reason,
+            } => write!(f, "{serial} failed to reconnect on {address}: {reason}"),
+        }
+    }
+}
+
+/// Keeps the devices adb paired with, or that were connected while watching,
+/// connected to their advertised connect service.
+#[derive(Debug, Default)]
+pub(crate) struct Watchdog {
+    /// Serials of the devices kept connected.
+    watched: HashSet<String>,
+    /// Where each device's connect service is advertised, by serial.
+    advertised: HashMap<String, String>,
+    /// Devices whose transport was up at the last check, with its address.
+    connected: HashMap<String, String>,
+    /// Where reconnecting each device last failed, it's tried again once it
+    /// advertises elsewhere or stops advertising.
+    failed: HashMap<String, String>,
+}
+
+fn is_up(devices: &[AdbDevice], serial: &str, address: &str) -> bool {
+    devices.iter().any(|device| {
+        device.state == TransportState::Device
+            && (device.serial == address
+                || device
+                    .instance_name()
+                    .is_some_and(|name| name.serial == serial))
+    })
+}
+
+impl Watchdog {
+    pub fn new(paired: impl IntoIterator<Item = String>) -> Watchdog {
+        Watchdog {
+            watched: paired.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Reconnects the watched devices whose transport is gone, or whose
+    /// connect service moved, given the connect services advertised.
+    pub fn check(
+        &mut self,
+        advertised: &HashSet<AdbService>,
+        client: &impl AdbClient,
+        on_event: &mut impl FnMut(&WatchEvent),
+    ) -> Result<(), String> {
+        let advertised: HashMap<String, String> = advertised
+            .iter()
+            .filter_map(|service| Some((service.adb_instance_name()?.serial, service.address())))
+            .collect();
+        let devices = client.list_devices()?;
+
+        for (serial, address) in &advertised {
+            let moved = self
+                .advertised
+                .get(serial)
+                .is_some_and(|previous| previous != address);
+            if is_up(&devices, serial, address) && !moved {
+                self.watched.insert(serial.clone());
+                self.connected.insert(serial.clone(), address.clone());
+                self.failed.remove(serial);
+                continue;
+            }
+            if !self.watched.contains(serial) || self.failed.get(serial) == Some(address) {
+                continue;
+            }
+            if moved {
+                on_event(&WatchEvent::Moved {
+                    serial: serial.clone(),
+                    address: address.clone(),
+                });
+                if let Some(previous) = self.connected.remove(serial) {
+                    // The old transport may linger as offline, it's fine if it's gone already
+                    let _ = client.adb_disconnect(&previous);
+                }
+            }
+            self.reconnect(serial, address, client, on_event);
+        }
+
+        // Devices that stopped advertising, e.g. asleep, reconnect once they're back
+        self.connected.retain(|serial, address| {
+            let up = advertised.contains_key(serial) || is_up(&devices, serial, address);
+            if !up {
+                on_event(&WatchEvent::Disconnected {
+                    serial: serial.clone(),
+                });
+            }
+            up
+        });
+        self.failed
+            .retain(|serial, _| advertised.contains_key(serial));
+        self.advertised = advertised;
+        Ok(())
+    }
+
+    fn reconnect(
+        &mut self,
+        serial: &str,
+        address: &str,
+        client: &impl AdbClient,
+        on_event: &mut impl FnMut(&WatchEvent),
+    ) {
+        if self.connected.remove(serial).is_some() {
+            on_event(&WatchEvent::Disconnected {
+                serial: serial.to_string(),
+            });
+        }
+        match client.adb_connect(address) {
+            Ok(()) => {
+                self.failed.remove(serial);
+                self.connected
+                    .insert(serial.to_string(), address.to_string());
+                on_event(&WatchEvent::Reconnected {
+                    serial: serial.to_string(),
+                    address: address.to_string(),
+                });
+            }
+            Err(reason) => {
+                self.failed.insert(serial.to_string(), address.to_string());
+                on_event(&WatchEvent::ReconnectFailed {
+                    serial: serial.to_string(),
+                    address: address.to_string(),
+                    reason,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        adb_mdns_discovery_service::AdbServiceType,
+        client::RustAdbClient,
+        test_support::{FakeAdbServer, FakeDevice},
+    };
+
+    const SERIAL: &str = "wg858lj7t959helz";
+
+    fn connect_service(port: u16) -> HashSet<AdbService> {
+        HashSet::from([AdbService::new(
+            AdbServiceType::Connect,
+            format!("adb-{SERIAL}-si5LWZ"),
+            "127.0.0.1",
+            port,
+        )])
+    }
+
+    /// A paired device accepting connections on ports 34003 and 34005.
+    fn paired_device() -> FakeAdbServer {
+        let server = FakeAdbServer::start().unwrap();
+        for port in [34003, 34005] {
+            server.add_device(FakeDevice {
+                pairing_address: "127.0.0.1:34317".into(),
+                pair_code: "123456".parse().unwrap(),
+                connect_address: format!("127.0.0.1:{port}"),
+            });
+        }
+        RustAdbClient::with_server(server.address())
+            .adb_pair("127.0.0.1:34317", &"123456".parse().unwrap())
+            .unwrap();
+        server
+    }
+
+    fn check(
+        watchdog: &mut Watchdog,
+        advertised: &HashSet<AdbService>,
+        client: &RustAdbClient,
+    ) -> Vec<WatchEvent> {
+        let mut events = Vec::new();
+        watchdog
+            .check(advertised, client, &mut |event| events.push(event.clone()))
+            .unwrap();
+        events
+    }
+
+    fn reconnected(port: u16) -> WatchEvent {
+        WatchEvent::Reconnected {
+            serial: SERIAL.into(),
+            address: format!("127.0.0.1:{port}"),
+        }
+    }
+
+    #[test]
+    fn test_reconnects_dropped_device() {
+        let server = paired_device();
+        let client = RustAdbClient::with_server(server.address());
+        client.adb_connect("127.0.0.1:34003").unwrap();
+        let mut watchdog = Watchdog::default();
+
+        assert_eq!(check(&mut watchdog, &connect_service(34003), &client), []);
+        client.adb_disconnect("127.0.0.1:34003").unwrap();
+
+        assert_eq!(
+            check(&mut watchdog, &connect_service(34003), &client),
+            [
+                WatchEvent::Disconnected {
+                    serial: SERIAL.into()
+                },
+                reconnected(34003)
+            ]
+        );
+        assert_eq!(server.connected(), ["127.0.0.1:34003"]);
+    }
+
+    #[test]
+    fn test_follows_device_to_new_port() {
+        let server = paired_device();
+        let client = RustAdbClient::with_server(server.address());
+        client.adb_connect("127.0.0.1:34003").unwrap();
+        let mut watchdog = Watchdog::default();
+        check(&mut watchdog, &connect_service(34003), &client);
+
+        let events = check(&mut watchdog, &connect_service(34005), &client);
+
+        assert_eq!(
+            events,
+            [
+                WatchEvent::Moved {
+                    serial: SERIAL.into(),
+                    address: "127.0.0.1:34005".into()
+                },
+                reconnected(34005)
+            ]
+        );
+        assert_eq!(server.connected(), ["127.0.0.1:34005"]);
+    }
+
+    #[test]
+    fn test_reconnects_paired_device_once_back() {
+        let server = paired_device();
+        let client = RustAdbClient::with_server(server.address());
+        let mut watchdog = Watchdog::new([SERIAL.to_string()]);
+
+        assert_eq!(check(&mut watchdog, &HashSet::new(), &client), []);
+        assert_eq!(
+            check(&mut watchdog, &connect_service(34005), &client),
+            [reconnected(34005)]
+        );
+    }
+
+    #[test]
+    fn test_reports_failed_reconnect_once() {
+        let server = paired_device();
+        let client = RustAdbClient::with_server(server.address());
+        let mut watchdog = Watchdog::new([SERIAL.to_string()]);
+
+        let events = check(&mut watchdog, &connect_service(34009), &client);
+        assert!(
+            matches!(events[..], [WatchEvent::ReconnectFailed { .. }]),
+            "{events:?}"
+        );
+        assert_eq!(check(&mut watchdog, &connect_service(34009), &client), []);
+        assert_eq!(server.requests().len(), 4, "{:?}", server.requests());
+
+        let events = check(&mut watchdog, &connect_service(34005), &client);
+        assert_eq!(events.last(), Some(&reconnected(34005)));
+    }
+
+    #[test]
+    fn test_ignores_unknown_devices() {
+        let server = FakeAdbServer::start().unwrap();
+        let client = RustAdbClient::with_server(server.address());
+        let mut watchdog = Watchdog::default();
+
+        assert_eq!(check(&mut watchdog, &connect_service(34003), &client), []);
+        assert_eq!(server.requests(), ["host:devices"]);
+    }
+
+    #[test]
+    fn test_reports_device_gone_to_sleep() {
+        let server = paired_device();
+        let client = RustAdbClient::with_server(server.address());
+        client.adb_connect("127.0.0.1:34003").unwrap();
+        let mut watchdog = Watchdog::default();
+        check(&mut watchdog, &connect_service(34003), &client);
+        client.adb_disconnect("127.0.0.1:34003").unwrap();
+
+        assert_eq!(
+            check(&mut watchdog, &HashSet::new(), &client),
+            [WatchEvent::Disconnected {
+                serial: SERIAL.into()
+            }]
+        );
+        assert_eq!(check(&mut watchdog, &HashSet::new(), &client), []);
+    }
+}
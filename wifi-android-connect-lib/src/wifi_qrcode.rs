@@ -0,0 +1,256 @@
+use crate::pair_code::PairCode;
+
+/// Maximum length in bytes of a DNS-SD instance label (RFC 6763 section 4.1.1).
+const MAX_SERVICE_NAME_LEN: usize = 63;
+
+/// Characters that have a special meaning inside a `WIFI:` URI field and
+/// must be escaped with a backslash.
+const SPECIAL_CHARS: [char; 5] = ['\\', ';', ',', ':', '"'];
+
+/// Validates that `name` can be advertised by Android as a mDNS instance name.
+pub fn validate_service_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Service name should not be empty".into());
+    }
+    if name.len() > MAX_SERVICE_NAME_LEN {
+        return Err(format!(
+            "Service name should have at most {MAX_SERVICE_NAME_LEN} bytes, got {}",
+            name.len()
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err("Service name should not contain control characters".into());
+    }
+    Ok(())
+}
+
+pub fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        if SPECIAL_CHARS.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Name and pair code carried by a `WIFI:T:ADB;S:<name>;P:<code>;;` payload.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AdbQrPayload {
+    pub name: String,
+    pub pair_code: PairCode,
+}
+
+impl AdbQrPayload {
+    /// Parses a `WIFI:T:ADB` payload, undoing the backslash escaping.
+    pub fn parse(payload: &str) -> Result<AdbQrPayload, String> {
+        let body = payload
+            .strip_prefix("WIFI:")
+            .ok_or_else(|| format!("Payload should start with WIFI: {payload}"))?;
+
+        let mut kind = None;
+        let mut name = None;
+        let mut password = None;
+
+        for (key, value) in split_fields(body)? {
+            match key.as_str() {
+                "T" => kind = Some(value),
+                "S" => name = Some(value),
+                "P" => password = Some(value),
+                _ => log::trace!("ignoring WIFI field {key}:{value}"),
+            }
+        }
+
+        match kind.as_deref() {
+            Some("ADB") => {}
+            Some(kind) => return Err(format!("Payload type should be ADB, got {kind}")),
+            None => return Err("Payload has no type field".into()),
+        }
+        let name = name.ok_or("Payload has no service name field")?;
+        let password = password.ok_or("Payload has no pair code field")?;
+        validate_service_name(&name)?;
+        let pair_code = password.parse()?;
+
+        Ok(AdbQrPayload { name, pair_code })
+    }
+
+    /// Decodes the first QR code found in the image at `path` and parses its payload.
+    #[cfg(feature = "qrcode-image")]
+    pub fn from_image(path: impl AsRef<std::path::Path>) -> Result<AdbQrPayload, String> {
+        let img = image::open(path).map_err(|e| e.to_string())?.to_luma8();
+        let mut img = rqrr::PreparedImage::prepare_from_greyscale(
+            img.width() as usize,
+            img.height() as usize,
+            |x, y| img.get_pixel(x as u32, y as u32).0[0],
+        );
+        let grids = img.detect_grids();
+        let grid = grids.first().ok_or("No QR code found in the image")?;
+        let (_meta, content) = grid.decode().map_err(|e| e.to_string())?;
+
+        Self::parse(&content)
+    }
+}
+
+/// Splits the body of a `WIFI:` payload into `key:value` fields, stopping at the
+/// terminating `;;`.
+fn split_fields(body: &str) -> Result<Vec<(String, String)>, String> {
+    let mut fields = vec![];
+    let mut current = String::new();
+    let mut chars = body.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) => current.push(c),
+                None => return Err("Payload ends with a dangling escape".into()),
+            },
+            ';' if current.is_empty() => {
+                if chars.next().is_some() {
+                    return Err("Payload has data after the terminator ;;".into());
+                }
+                return Ok(fields);
+            }
+            ';' => {
+                let (key, value) = current
+                    .split_once(':')
+                    .ok_or_else(|| format!("Malformed field {current}"))?;
+                fields.push((key.to_string(), value.to_string()));
+                current.clear();
+            }
+            c => current.push(c),
+        }
+    }
+
+    Err("Payload should end with ;;".into())
+}
+
+pub fn wifi_connect_msg(name: &str, pair_code: &PairCode) -> Result<String, String> {
+    validate_service_name(name)?;
+    Ok(format!(
+        "WIFI:T:ADB;S:{hostname};P:{password};;",
+        hostname = escape(name),
+        password = pair_code
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(code: &str) -> PairCode {
+        code.parse().unwrap()
+    }
+
+    #[test]
+    fn test_wifi_msg_6_digit() {
+        let msg = wifi_connect_msg("connectAndroid", &code("765912")).unwrap();
+        assert_eq!(msg, "WIFI:T:ADB;S:connectAndroid;P:765912;;");
+        let msg = wifi_connect_msg("connect Android", &code("123456")).unwrap();
+        assert_eq!(msg, "WIFI:T:ADB;S:connect Android;P:123456;;");
+        let msg = wifi_connect_msg("connect Android", &code("012345")).unwrap();
+        assert_eq!(msg, "WIFI:T:ADB;S:connect Android;P:012345;;");
+    }
+
+    #[test]
+    fn test_wifi_msg_escape_special_chars() {
+        let msg = wifi_connect_msg(r#"a;b:c,d\e"f"#, &code("123456")).unwrap();
+        assert_eq!(msg, r#"WIFI:T:ADB;S:a\;b\:c\,d\\e\"f;P:123456;;"#);
+    }
+
+    #[test]
+    fn test_wifi_msg_invalid_name() {
+        let too_long = "a".repeat(MAX_SERVICE_NAME_LEN + 1);
+        for name in ["", "tab\tname", "new\nline", too_long.as_str()] {
+            assert!(wifi_connect_msg(name, &code("123456")).is_err(), "{name:?}");
+        }
+        let longest = "a".repeat(MAX_SERVICE_NAME_LEN);
+        assert!(wifi_connect_msg(&longest, &code("123456")).is_ok());
+    }
+
+    #[test]
+    fn test_wifi_msg_round_trip() {
+        for name in [
+            "WIFI Android Connect",
+            "name;with;semicolons",
+            r"back\slash",
+            "colon:comma,quote\"",
+            "ação 📱",
+        ] {
+            let msg = wifi_connect_msg(name, &code("654321")).unwrap();
+            let payload = AdbQrPayload::parse(&msg).unwrap();
+            assert_eq!(payload.name, name);
+            assert_eq!(payload.pair_code, code("654321"));
+        }
+    }
+
+    #[test]
+    fn test_parse_field_order() {
+        let payload = AdbQrPayload::parse("WIFI:P:123456;S:studio-xyz;T:ADB;;").unwrap();
+        assert_eq!(
+            payload,
+            AdbQrPayload {
+                name: "studio-xyz".into(),
+                pair_code: code("123456")
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_payloads() {
+        for payload in [
+            "",
+            "WIFI:T:ADB;S:name;P:123456;",
+            "T:ADB;S:name;P:123456;;",
+            "WIFI:T:WPA;S:name;P:123456;;",
+            "WIFI:S:name;P:123456;;",
+            "WIFI:T:ADB;P:123456;;",
+            "WIFI:T:ADB;S:name;;",
+            "WIFI:T:ADB;S:name;P:12345;;",
+            "WIFI:T:ADB;S:name;P:12a456;;",
+            "WIFI:T:ADB;S:;P:123456;;",
+            "WIFI:T:ADB;S:name;P:123456;;trailing",
+            "WIFI:T:ADB;Sname;P:123456;;",
+            "WIFI:T:ADB;S:name\\",
+        ] {
+            assert!(AdbQrPayload::parse(payload).is_err(), "{payload:?}");
+        }
+    }
+
+    #[cfg(feature = "qrcode-image")]
+    #[test]
+    fn test_parse_qrcode_image() {
+        let msg = wifi_connect_msg("image;test", &code("024680")).unwrap();
+        let qr = qrcode::QrCode::new(&msg).unwrap();
+
+        let scale = 8;
+        let quiet_zone = 4;
+        let width = qr.width();
+        let size = ((width + 2 * quiet_zone) * scale) as u32;
+        let colors = qr.to_colors();
+        let img = image::GrayImage::from_fn(size, size, |x, y| {
+            let x = (x as usize / scale).checked_sub(quiet_zone);
+            let y = (y as usize / scale).checked_sub(quiet_zone);
+            match (x, y) {
+                (Some(x), Some(y)) if x < width && y < width => match colors[y * width + x] {
+                    qrcode::Color::Dark => image::Luma([0]),
+                    qrcode::Color::Light => image::Luma([255]),
+                },
+                _ => image::Luma([255]),
+            }
+        });
+
+        let path = std::env::temp_dir().join(format!("wifi-qrcode-{}.png", std::process::id()));
+        img.save(&path).unwrap();
+        let payload = AdbQrPayload::from_image(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            payload.unwrap(),
+            AdbQrPayload {
+                name: "image;test".into(),
+                pair_code: code("024680")
+            }
+        );
+    }
+}
@@ -12,7 +12,9 @@ use nvim_oxi::{
     Dictionary, Function, Object,
 };
 use serde::{Deserialize, Serialize};
-use wifi_android_connect_lib::WifiAndroidConnect;
+#[cfg(unix)]
+use wifi_android_connect_lib::{default_socket_path, DaemonClient, PairingRequest};
+use wifi_android_connect_lib::{PairCode, WifiAndroidConnect};
 
 const DEFAULT_TIMEOUT: u64 = 2 * 60;
 
@@ -41,7 +43,10 @@ fn setup(s: Rc<RefCell<Setup>>) -> impl Fn(Setup) {
 fn qrcode(s: Rc<RefCell<Setup>>) -> impl Fn(()) -> String {
     move |()| {
         let setup = &*(*s).borrow_mut();
-        let conn = WifiAndroidConnect::from(setup);
+        let conn = match wifi_android_connect(setup) {
+            Ok(conn) => conn,
+            Err(error_msg) => return error_msg,
+        };
 
         match conn.qrcode_img() {
             Ok(qrcode_img) => qrcode_img,
@@ -50,79 +55,143 @@ fn qrcode(s: Rc<RefCell<Setup>>) -> impl Fn(()) -> String {
     }
 }
 fn connect(setup: Rc<RefCell<Setup>>) -> impl Fn(Function<String, ()>) -> String {
-    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
-
-    let runtime = RUNTIME.get_or_init(|| tokio::runtime::Runtime::new().unwrap());
+    static RUNTIME: OnceLock<Result<tokio::runtime::Runtime, String>> = OnceLock::new();
 
     move |calback| {
+        // Errors are returned to lua instead of panicking inside Neovim
+        let runtime = match RUNTIME.get_or_init(|| {
+            tokio::runtime::Runtime::new().map_err(|e| format!("Unable to start tokio: {e}"))
+        }) {
+            Ok(runtime) => runtime,
+            Err(error_msg) => return error_msg.clone(),
+        };
+
         let setup = &*(*setup).borrow_mut();
-        let conn = WifiAndroidConnect::from(setup);
-        let timeout = setup.timeout_in_seconds.unwrap_or(DEFAULT_TIMEOUT);
-        let qrcode = conn.qrcode_img().unwrap();
 
         let (tx, rx) = mpsc::channel::<String>();
 
         let handle = nvim_oxi::libuv::AsyncHandle::new(move || {
-            let msg = rx.recv().unwrap();
-            calback.call(msg).unwrap();
-        })
-        .unwrap();
-
-        runtime.spawn(async move {
-            let timeout = tokio::time::timeout(Duration::from_secs(timeout), async {
-                conn.async_connect().await
-            })
-            .await;
-
-            let msg = match timeout {
-                Ok(connect_result) => match connect_result {
-                    Ok(_) => "Connected".into(),
-                    Err(e) => e,
-                },
-                Err(_) => "Timeout".into(),
+            let Ok(msg) = rx.try_recv() else {
+                return;
             };
-
-            tx.send(msg).unwrap();
-            handle.send().unwrap();
+            if let Err(e) = calback.call(msg) {
+                nvim_oxi::api::err_writeln(&format!("wifi-android-connect callback failed: {e}"));
+            }
         });
+        let handle = match handle {
+            Ok(handle) => handle,
+            Err(e) => return format!("Unable to register the connect callback: {e}"),
+        };
+
+        // Neovim may have exited meanwhile, nothing is left to notify then
+        let notify = move |result: Result<(), String>| {
+            let msg = match result {
+                Ok(()) => "Connected".into(),
+                Err(e) => e,
+            };
+            if tx.send(msg).is_ok() {
+                let _ = handle.send();
+            }
+        };
+
+        #[cfg(unix)]
+        if let Some(daemon) = running_daemon(setup) {
+            let session =
+                match pairing_request(setup).and_then(|request| daemon.start_pairing(&request)) {
+                    Ok(session) => session,
+                    Err(error_msg) => return error_msg,
+                };
+            std::thread::spawn(move || notify(daemon.wait(session.session, |_| {})));
+            return session.qrcode;
+        }
+
+        let conn = match wifi_android_connect(setup) {
+            Ok(conn) => conn,
+            Err(error_msg) => return error_msg,
+        };
+        let qrcode = match conn.qrcode_img() {
+            Ok(qrcode) => qrcode,
+            Err(error_msg) => return error_msg,
+        };
+
+        runtime.spawn(async move { notify(conn.async_connect().await) });
 
         qrcode
     }
 }
 
-impl From<Setup> for WifiAndroidConnect {
-    fn from(value: Setup) -> Self {
-        let mut conn = WifiAndroidConnect::default();
-        if let Some(code) = value.pair_code {
-            conn.pair_code = code;
-        }
-        if let Some(name) = value.pair_name {
-            conn.pair_name = name;
-        }
+/// The daemon to pair through, when one is running and the setup doesn't
+/// pick a backend, the daemon discovers with its own.
+#[cfg(unix)]
+fn running_daemon(setup: &Setup) -> Option<DaemonClient> {
+    if setup.backend.is_some() {
+        return None;
+    }
+    let socket = setup
+        .socket
+        .as_ref()
+        .map_or_else(default_socket_path, Into::into);
+    let daemon = DaemonClient::new(socket);
+    daemon.is_running().then_some(daemon)
+}
 
-        conn
+#[cfg(unix)]
+fn pairing_request(setup: &Setup) -> Result<PairingRequest, String> {
+    let pair_code = setup
+        .pair_code
+        .clone()
+        .map(PairCode::try_from)
+        .transpose()?;
+    Ok(PairingRequest {
+        name_prefix: setup.pair_name.clone(),
+        pair_code: pair_code.map(|code| code.to_string()),
+        timeout_secs: Some(setup.timeout_in_seconds.unwrap_or(DEFAULT_TIMEOUT)),
+    })
+}
+
+fn wifi_android_connect(setup: &Setup) -> Result<WifiAndroidConnect, String> {
+    let mut builder = WifiAndroidConnect::builder();
+    if let Some(code) = &setup.pair_code {
+        builder = builder.pair_code(code.clone().try_into()?);
+    }
+    if let Some(name) = &setup.pair_name {
+        builder = builder.pair_name_prefix(name);
     }
+    if let Some(backend) = &setup.backend {
+        builder = builder.backend(backend.parse()?);
+    }
+    let timeout = setup.timeout_in_seconds.unwrap_or(DEFAULT_TIMEOUT);
+
+    builder.timeout(Duration::from_secs(timeout)).build()
 }
 
-impl From<&Setup> for WifiAndroidConnect {
-    fn from(value: &Setup) -> Self {
-        let mut conn = WifiAndroidConnect::default();
-        if let Some(code) = value.pair_code {
-            conn.pair_code = code;
-        }
-        if let Some(name) = &value.pair_name {
-            conn.pair_name = name.clone();
-        }
+/// Pair code as given by lua, either `"012345"` or `123456`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum SetupPairCode {
+    Text(String),
+    Number(u32),
+}
 
-        conn
+impl TryFrom<SetupPairCode> for PairCode {
+    type Error = String;
+
+    fn try_from(value: SetupPairCode) -> Result<Self, Self::Error> {
+        match value {
+            SetupPairCode::Text(code) => code.parse(),
+            SetupPairCode::Number(code) => code.to_string().parse(),
+        }
     }
 }
 
 #[derive(Serialize, Deserialize)]
 struct Setup {
     pair_name: Option<String>,
-    pair_code: Option<u32>,
+    pair_code: Option<SetupPairCode>,
     timeout_in_seconds: Option<u64>,
+    backend: Option<String>,
+    /// Socket of the daemon to pair through when it's running
+    socket: Option<String>,
 }
 
 impl Default for Setup {
@@ -131,6 +200,8 @@ impl Default for Setup {
             pair_code: None,
             pair_name: None,
             timeout_in_seconds: Some(DEFAULT_TIMEOUT),
+            backend: None,
+            socket: None,
         }
     }
 }
@@ -0,0 +1,231 @@
+//! Plays the phone side of wireless debugging: advertises the pairing and
+//! connect mDNS services like Android does after scanning the QR code, and
+//! answers the host's pair and connect requests through a fake adb server.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use rand::{distributions::Alphanumeric, Rng};
+use wifi_android_connect_lib::{
+    test_support::{AdbReply, FakeAdbServer, FakeDevice, FakeMdnsResponder},
+    AdbInstanceName, AdbQrPayload, AdbServiceType,
+};
+
+/// Where the simulated adb server listens by default, next to adb's 5037.
+pub const DEFAULT_ADB_SERVER_PORT: u16 = 5038;
+
+/// How the simulated phone shows up on the network.
+#[derive(Debug, Clone)]
+pub struct PhoneOptions {
+    /// Device serial, random when not set.
+    pub serial: Option<String>,
+    /// Address of this host the services are advertised on.
+    pub ip: Ipv4Addr,
+    /// Port of the simulated adb server, `0` picks a free one.
+    pub adb_server_port: u16,
+}
+
+impl Default for PhoneOptions {
+    fn default() -> Self {
+        Self {
+            serial: None,
+            ip: Ipv4Addr::LOCALHOST,
+            adb_server_port: DEFAULT_ADB_SERVER_PORT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhoneState {
+    /// The pairing dialog is open.
+    WaitingForPairing,
+    /// The host paired, the pairing dialog is closed.
+    Paired,
+    /// The host connected.
+    Connected,
+}
+
+/// A phone with wireless debugging on, that just scanned a QR code.
+pub struct SimulatedPhone {
+    adb: FakeAdbServer,
+    mdns: FakeMdnsResponder,
+    pairing_name: String,
+    /// Full name of the pairing service while it's advertised.
+    pairing_service: Option<String>,
+    pairing_port: u16,
+    connect_name: AdbInstanceName,
+    connect_port: u16,
+    ip: Ipv4Addr,
+}
+
+fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// A port in the range adbd picks its services' ports from.
+fn random_port() -> u16 {
+    rand::thread_rng().gen_range(30000..=45000)
+}
+
+impl SimulatedPhone {
+    /// Advertises the services for `payload`, the pairing one named after the
+    /// payload and the connect one as `adb-<serial>-<suffix>`.
+    pub fn scan(payload: &AdbQrPayload, options: &PhoneOptions) -> Result<SimulatedPhone, String> {
+        let serial = options
+            .serial
+            .clone()
+            .unwrap_or_else(|| random_token(16).to_lowercase());
+        let connect_name = AdbInstanceName {
+            serial,
+            suffix: random_token(6),
+        };
+        let adb = FakeAdbServer::bind(SocketAddrV4::new(
+            Ipv4Addr::LOCALHOST,
+            options.adb_server_port,
+        ))?;
+        let mdns = FakeMdnsResponder::new(options.ip)?;
+        let mut phone = SimulatedPhone {
+            adb,
+            mdns,
+            pairing_name: payload.name.clone(),
+            pairing_service: None,
+            pairing_port: random_port(),
+            connect_name,
+            connect_port: random_port(),
+            ip: options.ip,
+        };
+        phone.adb.add_device(FakeDevice {
+            pairing_address: phone.pairing_address(),
+            pair_code: payload.pair_code,
+            connect_address: phone.connect_address(),
+        });
+        phone.advertise()?;
+        Ok(phone)
+    }
+
+    fn advertise(&mut self) -> Result<(), String> {
+        self.mdns.advertise(
+            AdbServiceType::Connect,
+            &self.connect_name.to_string(),
+            self.connect_port,
+        )?;
+        self.pairing_service = Some(self.mdns.advertise(
+            AdbServiceType::Pairing,
+            &self.pairing_name,
+            self.pairing_port,
+        )?);
+        self.update_adb_mdns_services();
+        Ok(())
+    }
+
+    /// Lists the advertised services in `host:mdns:services`, for the
+    /// adb-server discovery backend.
+    fn update_adb_mdns_services(&self) {
+        let mut services = format!(
+            "{}\t{}\t{}\n",
+            self.connect_name,
+            AdbServiceType::Connect.as_str(),
+            self.connect_address()
+        );
+        if self.pairing_service.is_some() {
+            services += &format!(
+                "{}\t{}\t{}\n",
+                self.pairing_name,
+                AdbServiceType::Pairing.as_str(),
+                self.pairing_address()
+            );
+        }
+        self.adb
+            .reply("host:mdns:services", AdbReply::Okay(services));
+    }
+
+    /// Address hosts should use as `ADB_SERVER_SOCKET`.
+    pub fn adb_server(&self) -> SocketAddrV4 {
+        self.adb.address()
+    }
+
+    pub fn pairing_address(&self) -> String {
+        format!("{}:{}", self.ip, self.pairing_port)
+    }
+
+    pub fn connect_address(&self) -> String {
+        format!("{}:{}", self.ip, self.connect_port)
+    }
+
+    pub fn connect_name(&self) -> &AdbInstanceName {
+        &self.connect_name
+    }
+
+    /// Closes the pairing dialog once the host paired, like Android does.
+    pub fn poll(&mut self) -> Result<PhoneState, String> {
+        if self.adb.connected().contains(&self.connect_address()) {
+            return Ok(PhoneState::Connected);
+        }
+        if !self.adb.paired().contains(&self.pairing_address()) {
+            return Ok(PhoneState::WaitingForPairing);
+        }
+        if let Some(fullname) = self.pairing_service.take() {
+            self.mdns.withdraw(&fullname)?;
+            self.update_adb_mdns_services();
+        }
+        Ok(PhoneState::Paired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wifi_android_connect_lib::{
+        test_support::loopback_interfaces, AdbClient, AdbMdns, RustAdbClient, WifiAndroidConnect,
+    };
+
+    use super::*;
+
+    fn options() -> PhoneOptions {
+        PhoneOptions {
+            serial: Some("wg858lj7t959helz".into()),
+            adb_server_port: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_host_connects_to_simulated_phone() {
+        let conn = WifiAndroidConnect::builder()
+            .pair_code("024680".parse().unwrap())
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let payload = AdbQrPayload::parse(&conn.qrcode_payload().unwrap()).unwrap();
+        let mut phone = SimulatedPhone::scan(&payload, &options()).unwrap();
+        assert_eq!(phone.poll(), Ok(PhoneState::WaitingForPairing));
+
+        let mdns = AdbMdns::with_interfaces(&loopback_interfaces()).unwrap();
+        let client = RustAdbClient::with_server(phone.adb_server());
+
+        assert_eq!(conn.connect_with(&mdns, &client), Ok(()));
+        assert_eq!(phone.poll(), Ok(PhoneState::Connected));
+        assert_eq!(phone.connect_name().serial, "wg858lj7t959helz");
+    }
+
+    #[test]
+    fn test_pairing_dialog_closes_after_pairing() {
+        let payload = AdbQrPayload {
+            name: "studio-k3J9aZ".into(),
+            pair_code: "123456".parse().unwrap(),
+        };
+        let mut phone = SimulatedPhone::scan(&payload, &options()).unwrap();
+        let client = RustAdbClient::with_server(phone.adb_server());
+
+        assert_eq!(
+            client.adb_pair(&phone.pairing_address(), &payload.pair_code),
+            Ok(())
+        );
+        assert_eq!(phone.poll(), Ok(PhoneState::Paired));
+        assert!(phone.pairing_service.is_none());
+    }
+}
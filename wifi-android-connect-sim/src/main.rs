@@ -0,0 +1,121 @@
+use std::{net::Ipv4Addr, time::Duration};
+
+use wifi_android_connect_lib::{AdbQrPayload, PairCode};
+use wifi_android_connect_sim::{PhoneOptions, PhoneState, SimulatedPhone, DEFAULT_ADB_SERVER_PORT};
+
+use clap::Parser;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// WIFI Android Connect simulator: plays an Android phone pairing over wireless debugging.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct SimArgs {
+    /// QR code payload, e.g. WIFI:T:ADB;S:name;P:123456;; (wifi-android-connect --payload prints it)
+    #[arg(short, long, conflicts_with_all = ["name", "code"])]
+    qr: Option<String>,
+
+    /// Image of the QR code to scan
+    #[cfg(feature = "qrcode-image")]
+    #[arg(long, conflicts_with_all = ["qr", "name", "code"])]
+    qr_image: Option<std::path::PathBuf>,
+
+    /// Pairing service name, when not scanning a QR code
+    #[arg(short, long, requires = "code")]
+    name: Option<String>,
+
+    /// 6 digits pair code, when not scanning a QR code
+    #[arg(short, long, requires = "name")]
+    code: Option<PairCode>,
+
+    /// Device serial, random by default
+    #[arg(short, long)]
+    serial: Option<String>,
+
+    /// Address of this host to advertise the services on
+    #[arg(long, default_value_t = Ipv4Addr::LOCALHOST)]
+    ip: Ipv4Addr,
+
+    /// Port of the simulated adb server, 0 picks a free one
+    #[arg(short, long, default_value_t = DEFAULT_ADB_SERVER_PORT)]
+    port: u16,
+
+    /// show the logs
+    #[arg(short, long)]
+    debug: bool,
+}
+
+impl SimArgs {
+    fn payload(&self) -> Result<AdbQrPayload, String> {
+        #[cfg(feature = "qrcode-image")]
+        if let Some(path) = &self.qr_image {
+            return AdbQrPayload::from_image(path);
+        }
+        match (&self.qr, &self.name, self.code) {
+            (Some(qr), _, _) => AdbQrPayload::parse(qr),
+            (None, Some(name), Some(pair_code)) => Ok(AdbQrPayload {
+                name: name.clone(),
+                pair_code,
+            }),
+            _ => Err("Give a QR code payload, or a name and a pair code".into()),
+        }
+    }
+}
+
+fn main() {
+    let args = SimArgs::parse();
+    if args.debug {
+        env_logger::builder()
+            .filter_module("wifi_android_connect", log::LevelFilter::Trace)
+            .init();
+    }
+
+    let payload = match args.payload() {
+        Ok(payload) => payload,
+        Err(msg) => {
+            println!("ERROR: {msg}");
+            return;
+        }
+    };
+    let options = PhoneOptions {
+        serial: args.serial.clone(),
+        ip: args.ip,
+        adb_server_port: args.port,
+    };
+    let mut phone = match SimulatedPhone::scan(&payload, &options) {
+        Ok(phone) => phone,
+        Err(msg) => {
+            println!("ERROR: {msg}");
+            return;
+        }
+    };
+
+    println!(
+        "Pairing service {} on {}",
+        payload.name,
+        phone.pairing_address()
+    );
+    println!(
+        "Connect service {} on {}",
+        phone.connect_name(),
+        phone.connect_address()
+    );
+    println!("Run the host against the simulated adb server:");
+    println!("  export ADB_SERVER_SOCKET=tcp:{}", phone.adb_server());
+    if args.ip.is_loopback() {
+        println!("  wifi-android-connect --interface 127.0.0.0/8");
+    }
+
+    let mut state = PhoneState::WaitingForPairing;
+    loop {
+        match phone.poll() {
+            Ok(new_state) if new_state != state => {
+                state = new_state;
+                println!("{state:?}");
+            }
+            Ok(_) => {}
+            Err(msg) => println!("ERROR: {msg}"),
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}